@@ -0,0 +1,567 @@
+use crate::builtin;
+use std::collections::BTreeMap;
+use std::env;
+
+// deepest a variable may refer to another expression before we give up
+const MAX_DEPTH: usize = 64;
+
+#[derive(Debug,Clone,PartialEq)]
+enum Token {
+    Num(i64),
+    Name(String),
+    Op(&'static str),
+}
+
+// longest operators first so that the tokenizer can take the first match
+const OPERATORS: &[&str] = &[
+    "<<=", ">>=",
+    "**", "++", "--", "<<", ">>", "<=", ">=", "==", "!=", "&&", "||",
+    "*=", "/=", "%=", "+=", "-=", "&=", "^=", "|=",
+    "+", "-", "*", "/", "%", "<", ">", "&", "^", "|", "!", "~",
+    "=", "?", ":", ",", "(", ")",
+];
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+    Pow,
+    Shl,
+    Shr,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Eq,
+    Ne,
+    BitAnd,
+    BitXor,
+    BitOr,
+    And,
+    Or,
+}
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+enum UnOp {
+    Neg,
+    Plus,
+    Not,
+    BitNot,
+}
+
+#[derive(Debug)]
+enum Expr {
+    Num(i64),
+    Var(String),
+    Unary(UnOp,Box<Expr>),
+    IncDec {name: String, delta: i64, prefix: bool},
+    Binary(BinOp,Box<Expr>,Box<Expr>),
+    Cond(Box<Expr>,Box<Expr>,Box<Expr>),
+    Assign(String,Option<BinOp>,Box<Expr>),
+    Comma(Box<Expr>,Box<Expr>),
+}
+
+fn parse_number(text: &str) -> Result<i64,String> {
+    let (base, digits) = if let Some((base, digits)) = text.split_once('#') {
+        match base.parse::<u32>() {
+            Ok(base) if (2..=64).contains(&base) => (base, digits),
+            _ => return Err(format!("invalid arithmetic base (error token is \"{}\")",text)),
+        }
+    }
+    else if let Some(digits) = text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        (16, digits)
+    }
+    else if text.len() > 1 && text.starts_with('0') {
+        (8, &text[1..])
+    }
+    else {
+        (10, text)
+    };
+
+    if digits.is_empty() {
+        return Err(format!("invalid number (error token is \"{}\")",text));
+    }
+
+    let mut value: i64 = 0;
+    for c in digits.chars() {
+        // past base 36 lowercase and uppercase letters are different digits
+        let digit = match c {
+            '0'..='9' => c as u32 - '0' as u32,
+            'a'..='z' => c as u32 - 'a' as u32 + 10,
+            'A'..='Z' if base > 36 => c as u32 - 'A' as u32 + 36,
+            'A'..='Z' => c as u32 - 'A' as u32 + 10,
+            '@' => 62,
+            '_' => 63,
+            _ => return Err(format!("invalid number (error token is \"{}\")",text)),
+        };
+        if digit >= base {
+            return Err(format!("value too great for base (error token is \"{}\")",text));
+        }
+        value = value.wrapping_mul(base as i64).wrapping_add(digit as i64);
+    }
+    Ok(value)
+}
+
+fn tokenize(expr: &str) -> Result<Vec<Token>,String> {
+    let chars: Vec<char> = expr.chars().collect();
+    let mut tokens = Vec::new();
+    let mut pos = 0;
+
+    while pos < chars.len() {
+        let c = chars[pos];
+        if c.is_whitespace() {
+            pos += 1;
+        }
+        else if c.is_ascii_digit() {
+            let start = pos;
+            while pos < chars.len() && (chars[pos].is_ascii_alphanumeric() || matches!(chars[pos], '#' | '@' | '_')) {
+                pos += 1;
+            }
+            let text: String = chars[start..pos].iter().collect();
+            tokens.push(Token::Num(parse_number(&text)?));
+        }
+        else if c == '_' || c.is_ascii_alphabetic() {
+            let start = pos;
+            while pos < chars.len() && (chars[pos] == '_' || chars[pos].is_ascii_alphanumeric()) {
+                pos += 1;
+            }
+            tokens.push(Token::Name(chars[start..pos].iter().collect()));
+        }
+        else {
+            let rest: String = chars[pos..].iter().collect();
+            match OPERATORS.iter().find(|op| rest.starts_with(*op)) {
+                Some(op) => {
+                    tokens.push(Token::Op(op));
+                    pos += op.len();
+                },
+                None => return Err(format!("syntax error: invalid arithmetic operator (error token is \"{}\")",rest)),
+            }
+        }
+    }
+
+    Ok(tokens)
+}
+
+fn binary_op(op: &str) -> Option<(BinOp,u8)> {
+    let pair = match op {
+        "||" => (BinOp::Or, 1),
+        "&&" => (BinOp::And, 2),
+        "|" => (BinOp::BitOr, 3),
+        "^" => (BinOp::BitXor, 4),
+        "&" => (BinOp::BitAnd, 5),
+        "==" => (BinOp::Eq, 6),
+        "!=" => (BinOp::Ne, 6),
+        "<" => (BinOp::Lt, 7),
+        "<=" => (BinOp::Le, 7),
+        ">" => (BinOp::Gt, 7),
+        ">=" => (BinOp::Ge, 7),
+        "<<" => (BinOp::Shl, 8),
+        ">>" => (BinOp::Shr, 8),
+        "+" => (BinOp::Add, 9),
+        "-" => (BinOp::Sub, 9),
+        "*" => (BinOp::Mul, 10),
+        "/" => (BinOp::Div, 10),
+        "%" => (BinOp::Rem, 10),
+        _ => return None,
+    };
+    Some(pair)
+}
+
+fn assign_op(op: &str) -> Option<Option<BinOp>> {
+    let op = match op {
+        "=" => None,
+        "*=" => Some(BinOp::Mul),
+        "/=" => Some(BinOp::Div),
+        "%=" => Some(BinOp::Rem),
+        "+=" => Some(BinOp::Add),
+        "-=" => Some(BinOp::Sub),
+        "<<=" => Some(BinOp::Shl),
+        ">>=" => Some(BinOp::Shr),
+        "&=" => Some(BinOp::BitAnd),
+        "^=" => Some(BinOp::BitXor),
+        "|=" => Some(BinOp::BitOr),
+        _ => return None,
+    };
+    Some(op)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn peek_op(&self) -> Option<&'static str> {
+        match self.peek() {
+            Some(Token::Op(op)) => Some(op),
+            _ => None,
+        }
+    }
+
+    fn error(&self) -> String {
+        let rest: Vec<String> = self.tokens[self.pos..].iter().map(|token| match token {
+            Token::Num(num) => num.to_string(),
+            Token::Name(name) => name.clone(),
+            Token::Op(op) => op.to_string(),
+        }).collect();
+        format!("syntax error in expression (error token is \"{}\")",rest.join(" "))
+    }
+
+    fn expect(&mut self, op: &str) -> Result<(),String> {
+        if self.peek_op() == Some(op) {
+            self.pos += 1;
+            Ok(())
+        }
+        else {
+            Err(self.error())
+        }
+    }
+
+    fn comma(&mut self) -> Result<Expr,String> {
+        let mut expr = self.assignment()?;
+        while self.peek_op() == Some(",") {
+            self.pos += 1;
+            expr = Expr::Comma(Box::new(expr),Box::new(self.assignment()?));
+        }
+        Ok(expr)
+    }
+
+    fn assignment(&mut self) -> Result<Expr,String> {
+        if let (Some(Token::Name(name)), Some(Token::Op(op))) = (self.tokens.get(self.pos), self.tokens.get(self.pos + 1)) {
+            if let Some(op) = assign_op(op) {
+                let name = name.clone();
+                self.pos += 2;
+                let value = self.assignment()?;
+                return Ok(Expr::Assign(name,op,Box::new(value)));
+            }
+        }
+
+        let expr = self.conditional()?;
+        if self.peek_op().and_then(assign_op).is_some() {
+            return Err("attempted assignment to non-variable".to_string());
+        }
+        Ok(expr)
+    }
+
+    fn conditional(&mut self) -> Result<Expr,String> {
+        let cond = self.binary(1)?;
+        if self.peek_op() != Some("?") {
+            return Ok(cond);
+        }
+        self.pos += 1;
+        let then = self.comma()?;
+        self.expect(":")?;
+        let otherwise = self.conditional()?;
+        Ok(Expr::Cond(Box::new(cond),Box::new(then),Box::new(otherwise)))
+    }
+
+    // precedence climbing over the left associative binary operators
+    fn binary(&mut self, min_prec: u8) -> Result<Expr,String> {
+        let mut lhs = self.power()?;
+        while let Some((op, prec)) = self.peek_op().and_then(binary_op) {
+            if prec < min_prec {
+                break;
+            }
+            self.pos += 1;
+            let rhs = self.binary(prec + 1)?;
+            lhs = Expr::Binary(op,Box::new(lhs),Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    fn power(&mut self) -> Result<Expr,String> {
+        let base = self.unary()?;
+        if self.peek_op() == Some("**") {
+            self.pos += 1;
+            let exponent = self.power()?;
+            return Ok(Expr::Binary(BinOp::Pow,Box::new(base),Box::new(exponent)));
+        }
+        Ok(base)
+    }
+
+    fn unary(&mut self) -> Result<Expr,String> {
+        let op = match self.peek_op() {
+            Some("-") => UnOp::Neg,
+            Some("+") => UnOp::Plus,
+            Some("!") => UnOp::Not,
+            Some("~") => UnOp::BitNot,
+            Some(op @ ("++" | "--")) => {
+                self.pos += 1;
+                let name = match self.peek() {
+                    Some(Token::Name(name)) => name.clone(),
+                    _ => return Err(self.error()),
+                };
+                self.pos += 1;
+                let delta = if op == "++" { 1 } else { -1 };
+                return Ok(Expr::IncDec {name, delta, prefix: true});
+            },
+            _ => return self.postfix(),
+        };
+        self.pos += 1;
+        Ok(Expr::Unary(op,Box::new(self.unary()?)))
+    }
+
+    fn postfix(&mut self) -> Result<Expr,String> {
+        let primary = self.primary()?;
+        if let Expr::Var(name) = &primary {
+            if let Some(op @ ("++" | "--")) = self.peek_op() {
+                self.pos += 1;
+                let delta = if op == "++" { 1 } else { -1 };
+                return Ok(Expr::IncDec {name: name.clone(), delta, prefix: false});
+            }
+        }
+        Ok(primary)
+    }
+
+    fn primary(&mut self) -> Result<Expr,String> {
+        let expr = match self.peek() {
+            Some(Token::Num(num)) => Expr::Num(*num),
+            Some(Token::Name(name)) => Expr::Var(name.clone()),
+            Some(Token::Op("(")) => {
+                self.pos += 1;
+                let expr = self.comma()?;
+                self.expect(")")?;
+                return Ok(expr);
+            },
+            Some(_) => return Err(self.error()),
+            None => return Err("syntax error: operand expected".to_string()),
+        };
+        self.pos += 1;
+        Ok(expr)
+    }
+}
+
+fn apply(op: BinOp, lhs: i64, rhs: i64) -> Result<i64,String> {
+    let value = match op {
+        BinOp::Add => lhs.wrapping_add(rhs),
+        BinOp::Sub => lhs.wrapping_sub(rhs),
+        BinOp::Mul => lhs.wrapping_mul(rhs),
+        BinOp::Div | BinOp::Rem if rhs == 0 => return Err("division by 0".to_string()),
+        BinOp::Div => lhs.wrapping_div(rhs),
+        BinOp::Rem => lhs.wrapping_rem(rhs),
+        BinOp::Pow => {
+            if rhs < 0 {
+                return Err("exponent less than 0".to_string());
+            }
+            lhs.wrapping_pow(rhs.min(u32::MAX as i64) as u32)
+        },
+        BinOp::Shl => lhs.wrapping_shl(rhs as u32),
+        BinOp::Shr => lhs.wrapping_shr(rhs as u32),
+        BinOp::Lt => (lhs < rhs) as i64,
+        BinOp::Le => (lhs <= rhs) as i64,
+        BinOp::Gt => (lhs > rhs) as i64,
+        BinOp::Ge => (lhs >= rhs) as i64,
+        BinOp::Eq => (lhs == rhs) as i64,
+        BinOp::Ne => (lhs != rhs) as i64,
+        BinOp::BitAnd => lhs & rhs,
+        BinOp::BitXor => lhs ^ rhs,
+        BinOp::BitOr => lhs | rhs,
+        BinOp::And => (lhs != 0 && rhs != 0) as i64,
+        BinOp::Or => (lhs != 0 || rhs != 0) as i64,
+    };
+    Ok(value)
+}
+
+struct Evaluator<'a> {
+    variables: &'a mut BTreeMap<String,String>,
+    depth: usize,
+}
+
+impl Evaluator<'_> {
+    // variables are looked up the same way as $NAME, the environment first.
+    // their values may be expressions themselves
+    fn get(&mut self, name: &str) -> Result<i64,String> {
+        let value = match env::var(name) {
+            Ok(val) => val,
+            Err(_) => self.variables.get(name).cloned().unwrap_or_default(),
+        };
+        let value = value.trim();
+        if value.is_empty() {
+            return Ok(0);
+        }
+        if let Ok(num) = value.parse::<i64>() {
+            return Ok(num);
+        }
+
+        if self.depth >= MAX_DEPTH {
+            return Err(format!("{}: expression recursion level exceeded",name));
+        }
+        self.depth += 1;
+        let result = self.evaluate_str(value);
+        self.depth -= 1;
+        result
+    }
+
+    fn set(&mut self, name: &str, value: i64) {
+        builtin::variable(name,&value.to_string(),self.variables);
+    }
+
+    fn evaluate_str(&mut self, expr: &str) -> Result<i64,String> {
+        let tokens = tokenize(expr)?;
+        if tokens.is_empty() {
+            return Ok(0);
+        }
+        let mut parser = Parser {tokens, pos: 0};
+        let expr = parser.comma()?;
+        if parser.pos < parser.tokens.len() {
+            return Err(parser.error());
+        }
+        self.eval(&expr)
+    }
+
+    fn eval(&mut self, expr: &Expr) -> Result<i64,String> {
+        let value = match expr {
+            Expr::Num(num) => *num,
+            Expr::Var(name) => self.get(name)?,
+            Expr::Unary(op, operand) => {
+                let operand = self.eval(operand)?;
+                match op {
+                    UnOp::Neg => operand.wrapping_neg(),
+                    UnOp::Plus => operand,
+                    UnOp::Not => (operand == 0) as i64,
+                    UnOp::BitNot => !operand,
+                }
+            },
+            Expr::IncDec {name, delta, prefix} => {
+                let old = self.get(name)?;
+                let new = old.wrapping_add(*delta);
+                self.set(name,new);
+                if *prefix { new } else { old }
+            },
+            Expr::Binary(BinOp::And, lhs, rhs) => {
+                (self.eval(lhs)? != 0 && self.eval(rhs)? != 0) as i64
+            },
+            Expr::Binary(BinOp::Or, lhs, rhs) => {
+                (self.eval(lhs)? != 0 || self.eval(rhs)? != 0) as i64
+            },
+            Expr::Binary(op, lhs, rhs) => {
+                let lhs = self.eval(lhs)?;
+                let rhs = self.eval(rhs)?;
+                apply(*op,lhs,rhs)?
+            },
+            Expr::Cond(cond, then, otherwise) => {
+                if self.eval(cond)? != 0 {
+                    self.eval(then)?
+                }
+                else {
+                    self.eval(otherwise)?
+                }
+            },
+            Expr::Assign(name, op, value) => {
+                let mut value = self.eval(value)?;
+                if let Some(op) = op {
+                    value = apply(*op,self.get(name)?,value)?;
+                }
+                self.set(name,value);
+                value
+            },
+            Expr::Comma(first, second) => {
+                self.eval(first)?;
+                self.eval(second)?
+            },
+        };
+        Ok(value)
+    }
+}
+
+// evaluates an arithmetic expression, assignments end up in the environment
+// if the variable is exported and in the shell variables otherwise
+pub fn evaluate(expr: &str, variables: &mut BTreeMap<String,String>) -> Result<i64,String> {
+    Evaluator {variables, depth: 0}.evaluate_str(expr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(expr: &str) -> Result<i64,String> {
+        evaluate(expr,&mut BTreeMap::new())
+    }
+
+    #[test]
+    fn precedence() {
+        assert_eq!(eval("1 + 2 * 3"),Ok(7));
+        assert_eq!(eval("(1 + 2) * 3"),Ok(9));
+        assert_eq!(eval("10 - 4 - 3"),Ok(3));
+        assert_eq!(eval("2 ** 3 ** 2"),Ok(512));
+        assert_eq!(eval("-2 ** 2"),Ok(4));
+        assert_eq!(eval("1 << 2 + 1"),Ok(8));
+        assert_eq!(eval("1 | 2 ^ 3 & 2"),Ok(1));
+        assert_eq!(eval("1 < 2 == 1"),Ok(1));
+        assert_eq!(eval("0 || 1 && 0"),Ok(0));
+        assert_eq!(eval("!0 + ~0"),Ok(0));
+        assert_eq!(eval("1 ? 2 : 3 ? 4 : 5"),Ok(2));
+        assert_eq!(eval("0 ? 2 : 0 ? 4 : 5"),Ok(5));
+        assert_eq!(eval("1, 2, 3"),Ok(3));
+        assert_eq!(eval(""),Ok(0));
+    }
+
+    #[test]
+    fn bases() {
+        assert_eq!(eval("0x1F"),Ok(31));
+        assert_eq!(eval("017"),Ok(15));
+        assert_eq!(eval("2#1010"),Ok(10));
+        assert_eq!(eval("36#z"),Ok(35));
+        assert_eq!(eval("64#_"),Ok(63));
+        assert_eq!(eval("64#A"),Ok(36));
+        assert!(eval("08").is_err());
+        assert!(eval("2#102").is_err());
+        assert!(eval("65#1").is_err());
+    }
+
+    #[test]
+    fn division_errors() {
+        assert!(eval("1 / 0").is_err());
+        assert!(eval("1 % 0").is_err());
+        assert_eq!(eval("-7 / 2"),Ok(-3));
+        assert_eq!(eval("-7 % 2"),Ok(-1));
+    }
+
+    #[test]
+    fn short_circuit() {
+        let mut variables = BTreeMap::new();
+        assert_eq!(evaluate("0 && (ARITH_TEST_SC = 1)",&mut variables),Ok(0));
+        assert_eq!(evaluate("1 || (ARITH_TEST_SC = 1)",&mut variables),Ok(1));
+        assert_eq!(evaluate("0 ? (ARITH_TEST_SC = 1) : 2",&mut variables),Ok(2));
+        assert_eq!(variables.get("ARITH_TEST_SC"),None);
+    }
+
+    #[test]
+    fn assignment() {
+        let mut variables = BTreeMap::new();
+        assert_eq!(evaluate("ARITH_TEST_A = 5",&mut variables),Ok(5));
+        assert_eq!(evaluate("ARITH_TEST_A += 2",&mut variables),Ok(7));
+        assert_eq!(evaluate("ARITH_TEST_A <<= 1",&mut variables),Ok(14));
+        assert_eq!(evaluate("ARITH_TEST_A++",&mut variables),Ok(14));
+        assert_eq!(evaluate("++ARITH_TEST_A",&mut variables),Ok(16));
+        assert_eq!(evaluate("ARITH_TEST_A--",&mut variables),Ok(16));
+        assert_eq!(variables.get("ARITH_TEST_A").map(|value| value.as_str()),Some("15"));
+        assert!(evaluate("3 = 4",&mut variables).is_err());
+    }
+
+    #[test]
+    fn variables() {
+        let mut variables = BTreeMap::new();
+        variables.insert("ARITH_TEST_X".to_string(),"ARITH_TEST_Y + 1".to_string());
+        variables.insert("ARITH_TEST_Y".to_string(),"4".to_string());
+        variables.insert("ARITH_TEST_LOOP".to_string(),"ARITH_TEST_LOOP".to_string());
+        assert_eq!(evaluate("ARITH_TEST_X * 2",&mut variables),Ok(10));
+        assert_eq!(evaluate("ARITH_TEST_UNSET + 1",&mut variables),Ok(1));
+        assert!(evaluate("ARITH_TEST_LOOP",&mut variables).is_err());
+    }
+
+    #[test]
+    fn syntax_errors() {
+        assert!(eval("1 +").is_err());
+        assert!(eval("(1").is_err());
+        assert!(eval("1 2").is_err());
+        assert!(eval("1 $ 2").is_err());
+    }
+}
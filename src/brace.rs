@@ -0,0 +1,225 @@
+use crate::lexer::{Word,WordPart};
+
+// brace expansion only looks at unquoted characters, anything else is kept whole
+#[derive(Debug,Clone)]
+enum Piece {
+    Char(char),
+    Part(WordPart),
+}
+
+fn pieces(word: &Word) -> Vec<Piece> {
+    let mut pieces = Vec::new();
+    for part in word.parts.iter() {
+        match part {
+            WordPart::Literal(text) => pieces.extend(text.chars().map(Piece::Char)),
+            part => pieces.push(Piece::Part(part.clone())),
+        }
+    }
+    pieces
+}
+
+fn word_from(pieces: Vec<Piece>) -> Word {
+    let mut word = Word::default();
+    for piece in pieces {
+        match piece {
+            Piece::Char(c) => match word.parts.last_mut() {
+                Some(WordPart::Literal(text)) => text.push(c),
+                _ => word.parts.push(WordPart::Literal(c.to_string())),
+            },
+            Piece::Part(part) => word.parts.push(part),
+        }
+    }
+    word
+}
+
+fn is_char(piece: &Piece, expected: char) -> bool {
+    matches!(piece, Piece::Char(c) if *c == expected)
+}
+
+// finds the } closing the { at start and the commas at its top level
+fn closing_brace(pieces: &[Piece], start: usize) -> Option<(usize,Vec<usize>)> {
+    let mut depth = 0;
+    let mut commas = Vec::new();
+    for (i, piece) in pieces.iter().enumerate().skip(start) {
+        if is_char(piece,'{') {
+            depth += 1;
+        }
+        else if is_char(piece,'}') {
+            depth -= 1;
+            if depth == 0 {
+                return Some((i,commas));
+            }
+        }
+        else if is_char(piece,',') && depth == 1 {
+            commas.push(i);
+        }
+    }
+    None
+}
+
+fn number(text: &str) -> Option<i64> {
+    let digits = text.strip_prefix('-').unwrap_or(text);
+    if digits.is_empty() || !digits.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    text.parse().ok()
+}
+
+fn single_char(text: &str) -> Option<char> {
+    let mut chars = text.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) if c.is_ascii_alphabetic() => Some(c),
+        _ => None,
+    }
+}
+
+// expands x..y or x..y..incr between numbers or letters
+fn sequence(pieces: &[Piece]) -> Option<Vec<String>> {
+    let mut text = String::new();
+    for piece in pieces {
+        match piece {
+            Piece::Char(c) => text.push(*c),
+            Piece::Part(_) => return None,
+        }
+    }
+
+    let bounds: Vec<&str> = text.split("..").collect();
+    let (start, end, incr) = match bounds[..] {
+        [start, end] => (start, end, 1),
+        [start, end, incr] => (start, end, number(incr)?.checked_abs()?),
+        _ => return None,
+    };
+    let incr = incr.max(1);
+
+    if let (Some(first), Some(last)) = (number(start), number(end)) {
+        // a leading zero pads every number to the same width
+        let padded = |bound: &str| bound.trim_start_matches('-').len() > 1 && bound.trim_start_matches('-').starts_with('0');
+        let width = if padded(start) || padded(end) { start.len().max(end.len()) } else { 0 };
+
+        let mut values = Vec::new();
+        let mut value = first;
+        while (first <= last && value <= last) || (first > last && value >= last) {
+            if value < 0 {
+                values.push(format!("-{:0>width$}",-value,width = width.saturating_sub(1)));
+            }
+            else {
+                values.push(format!("{:0>width$}",value,width = width));
+            }
+            value = if first <= last { value.checked_add(incr)? } else { value.checked_sub(incr)? };
+        }
+        return Some(values);
+    }
+
+    let (first, last) = (single_char(start)? as i64, single_char(end)? as i64);
+    let step = if first <= last { incr } else { -incr };
+    let mut values = Vec::new();
+    let mut value = first;
+    while (step > 0 && value <= last) || (step < 0 && value >= last) {
+        values.push(char::from(value as u8).to_string());
+        value += step;
+    }
+    Some(values)
+}
+
+fn expand_pieces(pieces: Vec<Piece>) -> Vec<Vec<Piece>> {
+    let mut start = 0;
+    while start < pieces.len() {
+        if !is_char(&pieces[start],'{') {
+            start += 1;
+            continue;
+        }
+        let (end, commas) = match closing_brace(&pieces,start) {
+            Some(found) => found,
+            None => {
+                start += 1;
+                continue;
+            }
+        };
+
+        let prefix = &pieces[..start];
+        let suffix = &pieces[end + 1..];
+        let alternatives: Vec<Vec<Piece>> = if !commas.is_empty() {
+            let mut bounds = vec![start];
+            bounds.extend(commas);
+            bounds.push(end);
+            bounds.windows(2).map(|pair| pieces[pair[0] + 1..pair[1]].to_vec()).collect()
+        }
+        else if let Some(values) = sequence(&pieces[start + 1..end]) {
+            values.into_iter().map(|value| value.chars().map(Piece::Char).collect()).collect()
+        }
+        else {
+            start += 1;
+            continue;
+        };
+
+        let mut words = Vec::new();
+        for alternative in alternatives {
+            let mut expanded = prefix.to_vec();
+            expanded.extend(alternative);
+            expanded.extend_from_slice(suffix);
+            words.extend(expand_pieces(expanded));
+        }
+        return words;
+    }
+    vec![pieces]
+}
+
+// expands {a,b} and {x..y} in a word into the words they stand for
+pub fn expand(word: &Word) -> Vec<Word> {
+    if !word.parts.iter().any(|part| matches!(part, WordPart::Literal(text) if text.contains('{'))) {
+        return vec![word.clone()];
+    }
+    expand_pieces(pieces(word)).into_iter().map(word_from).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn expand_text(text: &str) -> Vec<String> {
+        let word = Word {parts: vec![WordPart::Literal(text.to_string())]};
+        expand(&word).iter().map(|word| word.to_string()).collect()
+    }
+
+    #[test]
+    fn alternatives() {
+        assert_eq!(expand_text("a{b,c}d"),["abd","acd"]);
+        assert_eq!(expand_text("{a,b}{1,2}"),["a1","a2","b1","b2"]);
+        assert_eq!(expand_text("x{a,{b,c}}"),["xa","xb","xc"]);
+        assert_eq!(expand_text("{,a}"),["","a"]);
+    }
+
+    #[test]
+    fn left_alone() {
+        assert_eq!(expand_text("{a}"),["{a}"]);
+        assert_eq!(expand_text("{a,b"),["{a,b"]);
+        assert_eq!(expand_text("a}b"),["a}b"]);
+        assert_eq!(expand_text("{1..}"),["{1..}"]);
+        assert_eq!(expand_text("{a..1}"),["{a..1}"]);
+    }
+
+    #[test]
+    fn sequences() {
+        assert_eq!(expand_text("{1..4}"),["1","2","3","4"]);
+        assert_eq!(expand_text("{3..1}"),["3","2","1"]);
+        assert_eq!(expand_text("{1..10..4}"),["1","5","9"]);
+        assert_eq!(expand_text("{10..1..-4}"),["10","6","2"]);
+        assert_eq!(expand_text("{-1..1}"),["-1","0","1"]);
+        assert_eq!(expand_text("{01..3}"),["01","02","03"]);
+        assert_eq!(expand_text("{-01..1}"),["-01","000","001"]);
+        assert_eq!(expand_text("{a..e..2}"),["a","c","e"]);
+        assert_eq!(expand_text("{c..a}"),["c","b","a"]);
+    }
+
+    #[test]
+    fn quoted_braces() {
+        let word = Word {parts: vec![
+            WordPart::Literal("a{".to_string()),
+            WordPart::SingleQuoted("b,c".to_string()),
+            WordPart::Literal(",d}".to_string()),
+        ]};
+        assert_eq!(expand(&word).len(),2);
+        let word = Word {parts: vec![WordPart::SingleQuoted("{a,b}".to_string())]};
+        assert_eq!(expand(&word),std::slice::from_ref(&word));
+    }
+}
@@ -1,25 +1,30 @@
+use crate::arith;
+use crate::function::Functions;
+use crate::options;
 use std::env;
-use std::path::Path;
+use std::path::{Path,PathBuf};
 use std::collections::BTreeMap;
 
-pub fn change_dir(argv: &Vec<String>) {
+pub fn change_dir(argv: &[String]) -> i32 {
     let path;
     if argv.len() == 1 {
         let key = "HOME";
         match env::var(key) {
             Err(_) => {
                 eprintln!("User's home not set!");
-                return;
+                return 1;
             }
             Ok(val) => {
                 path = Path::new(&val);
 
-                match env::set_current_dir(path) {
-                    Ok(_) => (),
-                    Err(e) => eprintln!("{}",e),
+                let old = env::current_dir();
+                if let Err(e) = env::set_current_dir(path) {
+                    eprintln!("{}",e);
+                    return 1;
                 }
+                update_pwd(old);
 
-                return;
+                return 0;
 
             }
         }
@@ -28,102 +33,275 @@ pub fn change_dir(argv: &Vec<String>) {
         path = Path::new(&argv[1]);
     }
 
-    match env::set_current_dir(path) {
-        Ok(_) => (),
-        Err(_) => eprintln!("cd: no such file or directory: {}",argv[1]),
+    let old = env::current_dir();
+    if env::set_current_dir(path).is_err() {
+        eprintln!("cd: no such file or directory: {}",argv[1]);
+        return 1;
     }
+    update_pwd(old);
+    0
 }
 
-pub fn alias(argv: &Vec<String>, aliases: &mut BTreeMap<String,(String,Vec<String>)>) {
+// keeps PWD and OLDPWD, used by ~+ and ~-, in line with the working directory
+fn update_pwd(old: std::io::Result<PathBuf>) {
+    if let Ok(old) = old {
+        env::set_var("OLDPWD",old);
+    }
+    if let Ok(new) = env::current_dir() {
+        env::set_var("PWD",new);
+    }
+}
+
+pub fn alias(argv: &[String], aliases: &mut BTreeMap<String,(String,Vec<String>)>) -> i32 {
 
     if argv.len() == 1 {
         for (key, value) in aliases.iter() {
-           print!("{} = {} ",key,value.0); 
+           print!("{} = {} ",key,value.0);
            for arg in value.1.iter() {
             print!("{} ",arg);
            }
-           println!("");
+           println!();
         }
-        return;
+        return 0;
     }
 
-    if argv.len() < 5 {
-        eprintln!("Not enough arguments for alias.");
-        return;
+    let mut i = 1;
+    while i < argv.len() {
+        let (key, value, used) = match assignment(&argv[i..]) {
+            Some(assignment) => assignment,
+            None => {
+                eprintln!("Equal sign (=) needed for alias.");
+                return 1;
+            }
+        };
+        i += used;
+        if key.is_empty() || key.contains(char::is_whitespace) || key.contains('/') {
+            eprintln!("alias: `{}': invalid alias name",key);
+            return 1;
+        }
+
+        let mut args: Vec<String> = value.split_whitespace().map(|arg| arg.to_string()).collect();
+        if args.is_empty() {
+            eprintln!("Not enough arguments for alias.");
+            return 1;
+        }
+        let cmd = args.remove(0);
+
+        aliases.insert(key.to_string(), (cmd,args));
     }
-    
-    let key = &argv[2];
+    0
+}
 
-    if argv[3].as_str() != "=" {
-        eprintln!("Equal sign (=) needed for alias.");
-        return;
+pub fn export(argv: &[String], variables: &BTreeMap<String,String>) -> i32 {
+    if argv.len() < 2 {
+        eprintln!("Not enough argument for exporting.");
+        return 1;
     }
-    let mut args: Vec<String> = Vec::new();
-    let cmd;
-    if argv[4].contains("'") {
-        let string = argv[4].clone().drain(1..argv[4].len()-1).collect::<String>();
-        let temp_args:Vec<&str> = string.split(" ").collect();
 
-        for arg in temp_args.iter() {
-            args.push(arg.to_string());
+    let mut status = 0;
+    let mut i = 1;
+    while i < argv.len() {
+        let (key, value, used) = match assignment(&argv[i..]) {
+            Some((key, value, used)) => (key,Some(value),used),
+            None => (argv[i].as_str(),None,1),
+        };
+        i += used;
+        // set_var panics on names it can't put in the environment
+        if !crate::parser::is_name(key) || value.is_some_and(|value| value.contains('\0')) {
+            eprintln!("export: `{}': not a valid identifier",key);
+            status = 1;
+            continue;
         }
-        cmd = args.drain(..1).collect::<String>().clone();
+        match value.or_else(|| variables.get(key).map(|value| value.as_str())) {
+            Some(value) => env::set_var(key,value),
+            None if env::var_os(key).is_some() => (),
+            None => {
+                eprintln!("Equal sign (=) needed for exporting");
+                status = 1;
+            },
+        }
+    }
+    status
+}
 
+// the name and value of NAME=value, or of the older NAME = value spread
+// over three words, with the number of words it takes
+pub fn assignment(words: &[String]) -> Option<(&str,&str,usize)> {
+    if words.len() >= 3 && words[1] == "=" {
+        return Some((&words[0],&words[2],3));
+    }
+    words.first()?.split_once('=').map(|(key, value)| (key,value,1))
+}
 
+// exported variables stay in the environment, so that $NAME sees the new value
+pub fn variable(key: &str, value: &str, variables: &mut BTreeMap<String,String>) {
+    if env::var_os(key).is_some() {
+        env::set_var(key,value);
     }
     else {
-        cmd = argv[4].clone();
+        variables.insert(key.to_string(),value.to_string());
     }
-
-    aliases.insert(key.to_string(), (cmd,args));
 }
 
-pub fn export(argv: &Vec<String>) {
-    if argv.len() < 5 {
-        eprintln!("Not enough argument for exporting.");
-        return;
+
+// local name[=value], the old value comes back when the function returns
+pub fn local(argv: &[String], variables: &mut BTreeMap<String,String>, functions: &mut Functions) -> i32 {
+    if !functions.in_function() {
+        eprintln!("local: can only be used in a function");
+        return 1;
     }
 
-    let key = &argv[2];
-    if argv[3].as_str() != "=" {
-        eprintln!("Equal sign (=) needed for exporting")
+    let mut status = 0;
+    for arg in argv[1..].iter() {
+        let (name, value) = match arg.split_once('=') {
+            Some((name, value)) => (name, Some(value)),
+            None => (arg.as_str(), None),
+        };
+        if !crate::parser::is_name(name) {
+            eprintln!("local: `{}': not a valid identifier",arg);
+            status = 1;
+            continue;
+        }
+
+        let old = env::var(name).ok().or_else(|| variables.get(name).cloned());
+        functions.save_local(name,old);
+        match value {
+            Some(value) => variable(name,value,variables),
+            None => {
+                variables.remove(name);
+            },
+        }
     }
-    
-    let args; //argv.clone().drain(3..).collect::<Vec<String>>().join(" ");
-    if argv[4].contains("'") {
-       args = argv[4].clone().drain(1..argv[4].len()-1).collect(); 
+    status
+}
+
+pub fn print_vars(variables: &BTreeMap<String,String>) {
+
+        for (key, value) in variables.iter() {
+           println!("{} = {} ",key,value);
+        }
+
+}
+
+pub fn let_expr(argv: &[String], variables: &mut BTreeMap<String,String>) -> i32 {
+    if argv.len() < 2 {
+        eprintln!("let: expression expected");
+        return 1;
     }
-    else {
-        args = argv[4].clone();
+
+    let mut last = 0;
+    for arg in argv[1..].iter() {
+        match arith::evaluate(arg,variables) {
+            Ok(val) => last = val,
+            Err(e) => {
+                eprintln!("let: {}: {}",arg.trim(),e);
+                return 1;
+            }
+        }
     }
 
-    env::set_var(key,args);    
+    if last == 0 { 1 } else { 0 }
 }
 
-pub fn variable(argv: &Vec<String>, variables: &mut BTreeMap<String,String>) {
-    let key = &argv[0];
-    if argv[1].as_str() != "=" {
-        eprintln!("Equal sign (=) needed for assigning variable.")
+pub fn shopt(argv: &[String]) -> i32 {
+    let mut value = None;
+    let mut names = &argv[1..];
+    if let Some(flag) = names.first() {
+        match flag.as_str() {
+            "-s" => value = Some(true),
+            "-u" => value = Some(false),
+            _ => (),
+        }
+        if value.is_some() {
+            names = &names[1..];
+        }
     }
-    
-    let args;
-    if argv[2].contains("'") {
-        args = argv[2].clone().drain(1..argv[2].len()-1).collect();
+
+    let names: Vec<&str> = if names.is_empty() {
+        options::SHOPT_NAMES.to_vec()
     }
     else {
-        args = argv[2].as_str().to_string();
+        names.iter().map(|name| name.as_str()).collect()
+    };
+
+    let mut status = 0;
+    for name in names {
+        let option = match options::shopt_option(name) {
+            Some(option) => option,
+            None => {
+                eprintln!("shopt: {}: invalid shell option name",name);
+                status = 1;
+                continue;
+            }
+        };
+
+        match value {
+            Some(value) => options::set(option,value),
+            None => {
+                let enabled = options::enabled(option);
+                println!("{:<15}\t{}",name,if enabled { "on" } else { "off" });
+                if !enabled {
+                    status = 1;
+                }
+            },
+        }
     }
-    
-    variables.insert(key.to_string(),args);
+    status
 }
 
+// set -C, set -o name and their + forms, which turn the option off.
+// set -o or +o on its own lists the options
+pub fn set(argv: &[String]) -> i32 {
+    if argv.len() == 1 {
+        return set(&["set".to_string(),"-o".to_string()]);
+    }
 
-pub fn print_vars(variables: &mut BTreeMap<String,String>) {
-    
-        for (key, value) in variables.iter() {
-           print!("{} = {} ",key,value); 
-           println!("");
+    let mut args = argv[1..].iter();
+    while let Some(arg) = args.next() {
+        let value = match arg.chars().next() {
+            Some('-') => true,
+            Some('+') => false,
+            _ => {
+                eprintln!("set: {}: invalid option",arg);
+                return 2;
+            }
+        };
+
+        if arg[1..] == *"o" {
+            let name = match args.next() {
+                Some(name) => name,
+                None => {
+                    for name in options::SET_NAMES {
+                        let enabled = options::enabled(options::set_option(name).unwrap());
+                        if value {
+                            println!("{:<15}\t{}",name,if enabled { "on" } else { "off" });
+                        }
+                        else {
+                            println!("set {}o {}",if enabled { "-" } else { "+" },name);
+                        }
+                    }
+                    return 0;
+                }
+            };
+            match options::set_option(name) {
+                Some(option) => options::set(option,value),
+                None => {
+                    eprintln!("set: {}: invalid option name",name);
+                    return 1;
+                }
+            }
+            continue;
         }
-        return;
 
+        for flag in arg.chars().skip(1) {
+            match options::set_flag(flag) {
+                Some(option) => options::set(option,value),
+                None => {
+                    eprintln!("set: {}{}: invalid option",&arg[..1],flag);
+                    return 2;
+                }
+            }
+        }
+    }
+    0
 }
@@ -0,0 +1,558 @@
+use crate::arith;
+use crate::brace;
+use crate::builtin;
+use crate::function::Functions;
+use crate::glob;
+use crate::lexer::{Anchor,Param,ParamOp,Word,WordPart};
+use crate::options::{self,FAILGLOB,NULLGLOB};
+use crate::pattern::{self,Pattern};
+use std::collections::BTreeMap;
+use nix::unistd::{getuid,User};
+use std::env;
+
+fn lookup(name: &str, variables: &BTreeMap<String,String>, functions: &Functions) -> Option<String> {
+    // positional parameters come from the function being run
+    if name.chars().all(|c| c.is_ascii_digit()) {
+        return match name.parse::<usize>().ok()? {
+            0 => Some("tsh".to_string()),
+            n => functions.args().get(n - 1).cloned(),
+        };
+    }
+    match name {
+        "?" => return Some(crate::last_status().to_string()),
+        "#" => return Some(functions.args().len().to_string()),
+        "@" | "*" => return Some(functions.args().join(" ")),
+        _ => (),
+    }
+
+    match env::var(name) {
+        Ok(val) => Some(val),
+        Err(_) => variables.get(name).cloned(),
+    }
+}
+
+// the directory named by ~, ~user, ~+ or ~-
+fn tilde_dir(user: &str) -> Option<String> {
+    match user {
+        "" => match env::var("HOME") {
+            Ok(home) => Some(home),
+            Err(_) => User::from_uid(getuid()).ok()?.map(|user| user.dir.to_string_lossy().into_owned()),
+        },
+        "+" => match env::var("PWD") {
+            Ok(pwd) => Some(pwd),
+            Err(_) => env::current_dir().ok().map(|dir| dir.to_string_lossy().into_owned()),
+        },
+        "-" => env::var("OLDPWD").ok(),
+        user => User::from_name(user).ok()?.map(|user| user.dir.to_string_lossy().into_owned()),
+    }
+}
+
+// splits an unquoted ~ prefix, up to the first slash, off the front of a word
+fn tilde_prefix(parts: &[WordPart]) -> Option<(String,Vec<WordPart>)> {
+    let text = match parts.first() {
+        Some(WordPart::Literal(text)) => text.strip_prefix('~')?,
+        _ => return None,
+    };
+    let end = match text.find('/') {
+        Some(end) => end,
+        None if parts.len() == 1 => text.len(),
+        None => return None,
+    };
+
+    let dir = tilde_dir(&text[..end])?;
+    let mut rest = Vec::new();
+    if end < text.len() {
+        rest.push(WordPart::Literal(text[end..].to_string()));
+    }
+    rest.extend(parts[1..].iter().cloned());
+    Some((dir,rest))
+}
+
+fn is_ifs_whitespace(c: char) -> bool {
+    matches!(c, ' ' | '\t' | '\n')
+}
+
+#[derive(Default)]
+struct Field {
+    text: String,
+    // the text with its quoted characters escaped, used for globbing
+    pattern: String,
+    // whether an unquoted wildcard appeared
+    glob: bool,
+}
+
+impl Field {
+    fn push(&mut self, c: char, quoted: bool) {
+        self.text.push(c);
+        if quoted && pattern::is_special(c) {
+            self.pattern.push('\\');
+        }
+        else if !quoted && matches!(c, '*' | '?' | '[') {
+            self.glob = true;
+        }
+        self.pattern.push(c);
+    }
+}
+
+// collects the fields a word expands to. quoted text is kept as is while
+// the results of unquoted expansions are split on whitespace
+#[derive(Default)]
+struct Fields {
+    fields: Vec<Field>,
+    current: Field,
+    has_current: bool,
+    // set while expanding a pattern, where nothing is split
+    pattern: bool,
+    // set inside ${VAR:-word}, whose unquoted text is split like any expansion
+    nested: bool,
+}
+
+impl Fields {
+    fn push_literal(&mut self, text: &str) {
+        text.chars().for_each(|c| self.current.push(c,false));
+        self.has_current = true;
+    }
+
+    fn push_quoted(&mut self, text: &str) {
+        text.chars().for_each(|c| self.current.push(c,true));
+        self.has_current = true;
+    }
+
+    fn push_split(&mut self, text: &str) {
+        for c in text.chars() {
+            if is_ifs_whitespace(c) {
+                if self.has_current {
+                    self.fields.push(std::mem::take(&mut self.current));
+                    self.has_current = false;
+                }
+            }
+            else {
+                self.current.push(c,false);
+                self.has_current = true;
+            }
+        }
+    }
+
+    fn end_field(&mut self) {
+        self.fields.push(std::mem::take(&mut self.current));
+        self.has_current = false;
+    }
+
+    // adds the result of an expansion, which is only split outside quotes
+    fn push_expansion(&mut self, text: &str, quoted: bool) {
+        if quoted {
+            self.push_quoted(text);
+        }
+        else if self.pattern {
+            self.push_literal(text);
+        }
+        else {
+            self.push_split(text);
+        }
+    }
+
+    // the finished fields, with unquoted wildcards matched against the filesystem
+    fn finish(mut self) -> Result<Vec<String>,String> {
+        if self.has_current {
+            self.fields.push(self.current);
+        }
+
+        let mut fields = Vec::new();
+        for field in self.fields {
+            if !field.glob {
+                fields.push(field.text);
+                continue;
+            }
+
+            let paths = glob::glob(&field.pattern);
+            if !paths.is_empty() {
+                fields.extend(paths);
+            }
+            else if options::enabled(&FAILGLOB) {
+                return Err(format!("no match: {}",field.text));
+            }
+            else if !options::enabled(&NULLGLOB) {
+                fields.push(field.text);
+            }
+        }
+        Ok(fields)
+    }
+}
+
+fn char_boundaries(text: &str) -> Vec<usize> {
+    let mut boundaries: Vec<usize> = text.char_indices().map(|(i, _)| i).collect();
+    boundaries.push(text.len());
+    boundaries
+}
+
+fn remove_prefix(value: &str, pattern: &Pattern, longest: bool) -> String {
+    let mut boundaries = char_boundaries(value);
+    if longest {
+        boundaries.reverse();
+    }
+    match boundaries.into_iter().find(|i| pattern.matches(&value[..*i])) {
+        Some(i) => value[i..].to_string(),
+        None => value.to_string(),
+    }
+}
+
+fn remove_suffix(value: &str, pattern: &Pattern, longest: bool) -> String {
+    let mut boundaries = char_boundaries(value);
+    if !longest {
+        boundaries.reverse();
+    }
+    match boundaries.into_iter().find(|i| pattern.matches(&value[*i..])) {
+        Some(i) => value[..i].to_string(),
+        None => value.to_string(),
+    }
+}
+
+// replaces the longest matches of the pattern, the first one or all of them
+fn replace(value: &str, pattern: &Pattern, replacement: &str, all: bool, anchor: Anchor) -> String {
+    let boundaries = char_boundaries(value);
+    match anchor {
+        Anchor::Start => {
+            match boundaries.iter().rev().find(|i| pattern.matches(&value[..**i])) {
+                Some(i) => format!("{}{}",replacement,&value[*i..]),
+                None => value.to_string(),
+            }
+        },
+        Anchor::End => {
+            match boundaries.iter().find(|i| pattern.matches(&value[**i..])) {
+                Some(i) => format!("{}{}",&value[..*i],replacement),
+                None => value.to_string(),
+            }
+        },
+        Anchor::Anywhere => {
+            let mut result = String::new();
+            let mut start = 0;
+            let mut replaced = false;
+            while start + 1 < boundaries.len() {
+                let from = boundaries[start];
+                let found = if replaced && !all {
+                    None
+                }
+                else {
+                    (start + 1..boundaries.len()).rev().find(|end| pattern.matches(&value[from..boundaries[*end]]))
+                };
+
+                match found {
+                    Some(end) => {
+                        result.push_str(replacement);
+                        start = end;
+                        replaced = true;
+                    },
+                    None => {
+                        result.push_str(&value[from..boundaries[start + 1]]);
+                        start += 1;
+                    },
+                }
+            }
+            result
+        },
+    }
+}
+
+fn substring(name: &str, value: &str, offset: i64, length: Option<i64>) -> Result<String,String> {
+    let chars: Vec<char> = value.chars().collect();
+    let len = chars.len() as i64;
+
+    // offsets and lengths come straight from arithmetic, so they can be
+    // anywhere in the range of an i64
+    let start = if offset < 0 { len.saturating_add(offset) } else { offset };
+    if start < 0 || start > len {
+        return Ok(String::new());
+    }
+    let end = match length {
+        None => len,
+        Some(length) if length < 0 => {
+            if len.saturating_add(length) < start {
+                return Err(format!("{}: {}: substring expression < 0",name,length));
+            }
+            len + length
+        },
+        Some(length) => start.saturating_add(length).min(len),
+    };
+
+    Ok(chars[start as usize..end as usize].iter().collect())
+}
+
+fn change_case(value: &str, pattern: &Pattern, any: bool, all: bool, upper: bool) -> String {
+    let mut result = String::new();
+    for (i, c) in value.chars().enumerate() {
+        let selected = (all || i == 0) && (any || pattern.matches(c.encode_utf8(&mut [0; 4])));
+        if !selected {
+            result.push(c);
+        }
+        else if upper {
+            result.extend(c.to_uppercase());
+        }
+        else {
+            result.extend(c.to_lowercase());
+        }
+    }
+    result
+}
+
+fn expand_param(param: &Param, quoted: bool, fields: &mut Fields, aliases: &mut BTreeMap<String,(String,Vec<String>)>, variables: &mut BTreeMap<String,String>, functions: &mut Functions) -> Result<(),String> {
+    let value = lookup(&param.name,variables,functions);
+    // with the colon an empty value counts as unset
+    let is_set = |colon: &bool| match &value {
+        Some(value) => !*colon || !value.is_empty(),
+        None => false,
+    };
+
+    match &param.op {
+        // "$@" gives every argument a field of its own
+        ParamOp::Plain if param.name == "@" && quoted => {
+            for (i, arg) in functions.args().iter().enumerate() {
+                if i > 0 {
+                    fields.end_field();
+                }
+                fields.push_quoted(arg);
+            }
+        },
+        ParamOp::Plain => fields.push_expansion(&value.unwrap_or_default(),quoted),
+        ParamOp::Length => {
+            let length = value.unwrap_or_default().chars().count();
+            fields.push_literal(&length.to_string());
+        },
+        ParamOp::Default(colon, word) => {
+            if is_set(colon) {
+                fields.push_expansion(&value.unwrap_or_default(),quoted);
+            }
+            else {
+                expand_nested(word,quoted,fields,aliases,variables,functions)?;
+            }
+        },
+        ParamOp::Assign(colon, word) => {
+            if is_set(colon) {
+                fields.push_expansion(&value.unwrap_or_default(),quoted);
+            }
+            else {
+                let value = expand_string(word,aliases,variables,functions)?;
+                builtin::variable(&param.name,&value,variables);
+                fields.push_expansion(&value,quoted);
+            }
+        },
+        ParamOp::Error(colon, word) => {
+            if is_set(colon) {
+                fields.push_expansion(&value.unwrap_or_default(),quoted);
+            }
+            else {
+                let mut message = expand_string(word,aliases,variables,functions)?;
+                if message.is_empty() {
+                    message = "parameter null or not set".to_string();
+                }
+                return Err(format!("{}: {}",param.name,message));
+            }
+        },
+        ParamOp::Alternative(colon, word) => {
+            if is_set(colon) {
+                expand_nested(word,quoted,fields,aliases,variables,functions)?;
+            }
+        },
+        ParamOp::RemovePrefix(longest, word) => {
+            let pattern = expand_pattern(word,aliases,variables,functions)?;
+            fields.push_expansion(&remove_prefix(&value.unwrap_or_default(),&pattern,*longest),quoted);
+        },
+        ParamOp::RemoveSuffix(longest, word) => {
+            let pattern = expand_pattern(word,aliases,variables,functions)?;
+            fields.push_expansion(&remove_suffix(&value.unwrap_or_default(),&pattern,*longest),quoted);
+        },
+        ParamOp::Replace {all, anchor, pattern, replacement} => {
+            let value = value.unwrap_or_default();
+            let pattern = expand_pattern(pattern,aliases,variables,functions)?;
+            let replacement = expand_string(replacement,aliases,variables,functions)?;
+            fields.push_expansion(&replace(&value,&pattern,&replacement,*all,*anchor),quoted);
+        },
+        ParamOp::Substring(offset, length) => {
+            let offset = expand_arith(offset,aliases,variables,functions)?;
+            let length = match length {
+                Some(length) => Some(expand_arith(length,aliases,variables,functions)?),
+                None => None,
+            };
+            let value = substring(&param.name,&value.unwrap_or_default(),offset,length)?;
+            fields.push_expansion(&value,quoted);
+        },
+        ParamOp::Upper(all, word) | ParamOp::Lower(all, word) => {
+            let upper = matches!(param.op, ParamOp::Upper(..));
+            let pattern = expand_pattern(word,aliases,variables,functions)?;
+            let value = change_case(&value.unwrap_or_default(),&pattern,word.parts.is_empty(),*all,upper);
+            fields.push_expansion(&value,quoted);
+        },
+    }
+    Ok(())
+}
+
+fn expand_nested(word: &Word, quoted: bool, fields: &mut Fields, aliases: &mut BTreeMap<String,(String,Vec<String>)>, variables: &mut BTreeMap<String,String>, functions: &mut Functions) -> Result<(),String> {
+    let nested = std::mem::replace(&mut fields.nested,true);
+    let result = expand_start(word,quoted,fields,aliases,variables,functions);
+    fields.nested = nested;
+    result
+}
+
+pub fn expand_arith(word: &Word, aliases: &mut BTreeMap<String,(String,Vec<String>)>, variables: &mut BTreeMap<String,String>, functions: &mut Functions) -> Result<i64,String> {
+    let expr = expand_string(word,aliases,variables,functions)?;
+    arith::evaluate(&expr,variables).map_err(|e| format!("{}: {}",expr.trim(),e))
+}
+
+pub fn expand_pattern(word: &Word, aliases: &mut BTreeMap<String,(String,Vec<String>)>, variables: &mut BTreeMap<String,String>, functions: &mut Functions) -> Result<Pattern,String> {
+    let mut fields = Fields {pattern: true, ..Default::default()};
+    expand_parts(&word.parts,false,&mut fields,aliases,variables,functions)?;
+    Ok(Pattern::new(&fields.current.pattern))
+}
+
+fn expand_parts(parts: &[WordPart], quoted: bool, fields: &mut Fields, aliases: &mut BTreeMap<String,(String,Vec<String>)>, variables: &mut BTreeMap<String,String>, functions: &mut Functions) -> Result<(),String> {
+    for part in parts.iter() {
+        match part {
+            WordPart::Literal(text) if fields.nested => fields.push_expansion(text,quoted),
+            WordPart::Literal(text) if quoted => fields.push_quoted(text),
+            WordPart::Literal(text) => fields.push_literal(text),
+            WordPart::SingleQuoted(text) => fields.push_quoted(text),
+            WordPart::Escaped(c) => fields.push_quoted(c.encode_utf8(&mut [0; 4])),
+            // "$@" without arguments leaves no field at all
+            WordPart::DoubleQuoted(inner) if functions.args().is_empty() && matches!(inner.as_slice(), [WordPart::Param(param)] if param.name == "@" && param.op == ParamOp::Plain) => (),
+            WordPart::DoubleQuoted(inner) => {
+                fields.push_quoted("");
+                expand_parts(inner,true,fields,aliases,variables,functions)?;
+            },
+            WordPart::Param(param) => expand_param(param,quoted,fields,aliases,variables,functions)?,
+            WordPart::CommandSub(text) => {
+                let val = crate::command_substitution(text,aliases,variables,functions);
+                fields.push_expansion(&val,quoted);
+            },
+            WordPart::ProcessSub(output, text) => {
+                let path = crate::process_substitution(text,*output,aliases,variables,functions)?;
+                fields.push_quoted(&path);
+            },
+            WordPart::Arith(inner) => {
+                let val = expand_arith(&Word {parts: inner.clone()},aliases,variables,functions)?;
+                fields.push_literal(&val.to_string());
+            },
+        }
+    }
+    Ok(())
+}
+
+// expands a whole word, starting with a possible ~ prefix
+fn expand_start(word: &Word, quoted: bool, fields: &mut Fields, aliases: &mut BTreeMap<String,(String,Vec<String>)>, variables: &mut BTreeMap<String,String>, functions: &mut Functions) -> Result<(),String> {
+    match tilde_prefix(&word.parts) {
+        Some((dir, rest)) => {
+            fields.push_quoted(&dir);
+            expand_parts(&rest,quoted,fields,aliases,variables,functions)
+        },
+        None => expand_parts(&word.parts,quoted,fields,aliases,variables,functions),
+    }
+}
+
+// expands a word into the fields it produces, including pathname expansion
+pub fn expand_word(word: &Word, aliases: &mut BTreeMap<String,(String,Vec<String>)>, variables: &mut BTreeMap<String,String>, functions: &mut Functions) -> Result<Vec<String>,String> {
+    let mut fields = Fields::default();
+    expand_start(word,false,&mut fields,aliases,variables,functions)?;
+    fields.finish()
+}
+
+// expands a word that is never split, like the value of an assignment
+pub fn expand_string(word: &Word, aliases: &mut BTreeMap<String,(String,Vec<String>)>, variables: &mut BTreeMap<String,String>, functions: &mut Functions) -> Result<String,String> {
+    let mut fields = Fields::default();
+    expand_start(word,true,&mut fields,aliases,variables,functions)?;
+    Ok(fields.current.text)
+}
+
+pub fn expand_words(words: &[Word], aliases: &mut BTreeMap<String,(String,Vec<String>)>, variables: &mut BTreeMap<String,String>, functions: &mut Functions) -> Result<Vec<String>,String> {
+    let mut fields = Vec::new();
+    for word in words.iter() {
+        for word in brace::expand(word) {
+            fields.extend(expand_word(&word,aliases,variables,functions)?);
+        }
+    }
+    Ok(fields)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::{Lexer,Token};
+
+    fn expand(text: &str, variables: &mut BTreeMap<String,String>) -> Result<Vec<String>,String> {
+        let word = match Lexer::new(text).next_token().unwrap() {
+            Token::Word(word) => word,
+            token => panic!("not a word: {:?}",token),
+        };
+        expand_word(&word,&mut BTreeMap::new(),variables,&mut Functions::new())
+    }
+
+    fn variables() -> BTreeMap<String,String> {
+        let mut variables = BTreeMap::new();
+        variables.insert("EXPAND_TEST_X".to_string(),"abcabc".to_string());
+        variables.insert("EXPAND_TEST_PATH".to_string(),"/usr/lib/libc.so.6".to_string());
+        variables.insert("EXPAND_TEST_EMPTY".to_string(),String::new());
+        variables
+    }
+
+    fn one(text: &str) -> String {
+        expand(text,&mut variables()).unwrap().join(" ")
+    }
+
+    #[test]
+    fn field_splitting() {
+        let mut variables = variables();
+        variables.insert("EXPAND_TEST_WORDS".to_string()," a  b\tc ".to_string());
+        assert_eq!(expand("$EXPAND_TEST_WORDS",&mut variables).unwrap(),["a","b","c"]);
+        assert_eq!(expand("x${EXPAND_TEST_WORDS}y",&mut variables).unwrap(),["x","a","b","c","y"]);
+        // quotes keep the value whole, and an empty one only stays when quoted
+        assert_eq!(expand("\"$EXPAND_TEST_WORDS\"",&mut variables).unwrap(),[" a  b\tc "]);
+        assert_eq!(expand("$EXPAND_TEST_EMPTY",&mut variables).unwrap(),Vec::<String>::new());
+        assert_eq!(expand("\"$EXPAND_TEST_EMPTY\"",&mut variables).unwrap(),[""]);
+        assert_eq!(expand("'$EXPAND_TEST_X'",&mut variables).unwrap(),["$EXPAND_TEST_X"]);
+    }
+
+    #[test]
+    fn defaults() {
+        assert_eq!(one("${EXPAND_TEST_UNSET-d}"),"d");
+        assert_eq!(one("${EXPAND_TEST_EMPTY-d}"),"");
+        assert_eq!(one("${EXPAND_TEST_EMPTY:-d}"),"d");
+        assert_eq!(one("${EXPAND_TEST_X:+alt}"),"alt");
+        assert_eq!(one("${EXPAND_TEST_UNSET:+alt}"),"");
+        assert!(expand("${EXPAND_TEST_UNSET:?gone}",&mut variables()).is_err());
+
+        let mut variables = variables();
+        assert_eq!(expand("${EXPAND_TEST_NEW:=set}",&mut variables).unwrap(),["set"]);
+        assert_eq!(variables.get("EXPAND_TEST_NEW").map(|value| value.as_str()),Some("set"));
+    }
+
+    #[test]
+    fn patterns() {
+        assert_eq!(one("${#EXPAND_TEST_X}"),"6");
+        assert_eq!(one("${EXPAND_TEST_PATH#*/}"),"usr/lib/libc.so.6");
+        assert_eq!(one("${EXPAND_TEST_PATH##*/}"),"libc.so.6");
+        assert_eq!(one("${EXPAND_TEST_PATH%.*}"),"/usr/lib/libc.so");
+        assert_eq!(one("${EXPAND_TEST_PATH%%.*}"),"/usr/lib/libc");
+        assert_eq!(one("${EXPAND_TEST_X/b/B}"),"aBcabc");
+        assert_eq!(one("${EXPAND_TEST_X//b/B}"),"aBcaBc");
+        assert_eq!(one("${EXPAND_TEST_X/#a/A}"),"Abcabc");
+        assert_eq!(one("${EXPAND_TEST_X/%c/C}"),"abcabC");
+        assert_eq!(one("${EXPAND_TEST_X^}"),"Abcabc");
+        assert_eq!(one("${EXPAND_TEST_X^^[ab]}"),"ABcABc");
+    }
+
+    #[test]
+    fn substrings() {
+        assert_eq!(one("${EXPAND_TEST_X:2}"),"cabc");
+        assert_eq!(one("${EXPAND_TEST_X:1:3}"),"bca");
+        assert_eq!(one("${EXPAND_TEST_X: -2}"),"bc");
+        assert_eq!(one("${EXPAND_TEST_X:1:-1}"),"bcab");
+        assert_eq!(one("${EXPAND_TEST_X:9}"),"");
+        assert!(expand("${EXPAND_TEST_X:4:-3}",&mut variables()).is_err());
+    }
+
+    #[test]
+    fn substring_bounds() {
+        assert_eq!(substring("x","abc",1,Some(i64::MAX)),Ok("bc".to_string()));
+        assert_eq!(substring("x","abc",i64::MAX,Some(i64::MAX)),Ok(String::new()));
+        assert_eq!(substring("x","abc",i64::MIN,None),Ok(String::new()));
+        assert!(substring("x","abc",0,Some(i64::MIN)).is_err());
+        assert_eq!(one("${EXPAND_TEST_X:1:9223372036854775807}"),"bcabc");
+    }
+}
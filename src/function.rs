@@ -0,0 +1,62 @@
+use crate::parser::Command;
+use std::collections::BTreeMap;
+use std::rc::Rc;
+
+// a running function call. locals keeps the values the local builtin hid,
+// to be put back when the call returns
+struct Frame {
+    args: Vec<String>,
+    locals: Vec<(String,Option<String>)>,
+}
+
+pub struct Functions {
+    definitions: BTreeMap<String,Rc<Command>>,
+    frames: Vec<Frame>,
+}
+
+impl Functions {
+    pub fn new() -> Self {
+        Self {definitions: BTreeMap::new(), frames: Vec::new()}
+    }
+
+    pub fn define(&mut self, name: &str, body: Command) {
+        self.definitions.insert(name.to_string(),Rc::new(body));
+    }
+
+    pub fn get(&self, name: &str) -> Option<Rc<Command>> {
+        self.definitions.get(name).cloned()
+    }
+
+    pub fn in_function(&self) -> bool {
+        !self.frames.is_empty()
+    }
+
+    // the positional parameters $1 to $N
+    pub fn args(&self) -> &[String] {
+        match self.frames.last() {
+            Some(frame) => &frame.args,
+            None => &[],
+        }
+    }
+
+    pub fn push_frame(&mut self, args: &[String]) {
+        self.frames.push(Frame {args: args.to_vec(), locals: Vec::new()});
+    }
+
+    // ends the innermost call and returns the variables to restore, last hidden first
+    pub fn pop_frame(&mut self) -> Vec<(String,Option<String>)> {
+        match self.frames.pop() {
+            Some(frame) => frame.locals.into_iter().rev().collect(),
+            None => Vec::new(),
+        }
+    }
+
+    // remembers the value a local hides, once per call
+    pub fn save_local(&mut self, name: &str, value: Option<String>) {
+        if let Some(frame) = self.frames.last_mut() {
+            if !frame.locals.iter().any(|(local, _)| local == name) {
+                frame.locals.push((name.to_string(),value));
+            }
+        }
+    }
+}
@@ -0,0 +1,108 @@
+use crate::options::{self,DOTGLOB,GLOBSTAR};
+use crate::pattern::Pattern;
+use std::fs;
+
+fn join(dir: &str, name: &str) -> String {
+    if dir.is_empty() {
+        name.to_string()
+    }
+    else if dir.ends_with('/') {
+        format!("{}{}",dir,name)
+    }
+    else {
+        format!("{}/{}",dir,name)
+    }
+}
+
+// names in a directory, without . and ..
+fn entries(dir: &str) -> Vec<String> {
+    let dir = if dir.is_empty() { "." } else { dir };
+    match fs::read_dir(dir) {
+        Ok(entries) => entries.filter_map(|entry| entry.ok())
+            .filter_map(|entry| entry.file_name().into_string().ok())
+            .collect(),
+        Err(_) => Vec::new(),
+    }
+}
+
+fn is_dir(path: &str) -> bool {
+    let path = if path.is_empty() { "." } else { path };
+    fs::metadata(path).is_ok_and(|meta| meta.is_dir())
+}
+
+fn exists(path: &str) -> bool {
+    fs::symlink_metadata(path).is_ok()
+}
+
+// everything below dir for **, symbolic links are not followed
+fn descendants(dir: &str, dirs_only: bool, found: &mut Vec<String>) {
+    for name in entries(dir) {
+        if name.starts_with('.') && !options::enabled(&DOTGLOB) {
+            continue;
+        }
+        let path = join(dir,&name);
+        let is_dir = fs::symlink_metadata(&path).is_ok_and(|meta| meta.is_dir());
+        if is_dir || !dirs_only {
+            found.push(path.clone());
+        }
+        if is_dir {
+            descendants(&path,dirs_only,found);
+        }
+    }
+}
+
+// matches a pattern with quoted characters escaped against the filesystem.
+// returns the sorted paths, empty when nothing matches
+pub fn glob(pattern: &str) -> Vec<String> {
+    let (mut paths, rest) = match pattern.strip_prefix('/') {
+        Some(rest) => (vec!["/".to_string()], rest),
+        None => (vec![String::new()], pattern),
+    };
+
+    let components: Vec<&str> = rest.split('/').collect();
+    for (i, component) in components.iter().enumerate() {
+        let last = i + 1 == components.len();
+
+        // a trailing slash only keeps directories
+        if component.is_empty() {
+            paths = paths.into_iter().filter(|path| is_dir(path)).map(|path| join(&path,"")).collect();
+            continue;
+        }
+
+        if *component == "**" && options::enabled(&GLOBSTAR) {
+            let mut found = Vec::new();
+            for path in paths.iter() {
+                if !last {
+                    found.push(path.clone());
+                }
+                descendants(path,!last,&mut found);
+            }
+            paths = found;
+            continue;
+        }
+
+        let pattern = Pattern::new(component);
+        if pattern.is_literal() {
+            let name = pattern.literal_text();
+            paths = paths.iter().map(|path| join(path,&name)).collect();
+            continue;
+        }
+
+        // hidden files only match a pattern that starts with a dot
+        let hidden = component.starts_with('.') || component.starts_with("\\.") || options::enabled(&DOTGLOB);
+        let mut found = Vec::new();
+        for path in paths.iter() {
+            let mut names: Vec<String> = entries(path).into_iter()
+                .filter(|name| hidden || !name.starts_with('.'))
+                .filter(|name| pattern.matches(name))
+                .collect();
+            names.sort();
+            found.extend(names.iter().map(|name| join(path,name)));
+        }
+        paths = found;
+    }
+
+    let mut paths: Vec<String> = paths.into_iter().filter(|path| !path.is_empty() && exists(path)).collect();
+    paths.sort();
+    paths
+}
@@ -1,8 +1,12 @@
 
+use std::collections::BTreeMap;
 use std::fmt;
+use std::fs::File;
+use std::io::Write;
+use nix::sys::signal::Signal;
+use nix::sys::termios::Termios;
 
 pub enum ProccessState {
-    UNDEF,
     FG,
     BG,
     ST
@@ -11,7 +15,6 @@ pub enum ProccessState {
 impl fmt::Display for ProccessState {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            ProccessState::UNDEF => write!(f,"UNDEF"),
             ProccessState::FG => write!(f,"FG"),
             ProccessState::BG => write!(f,"BG"),
             ProccessState::ST => write!(f,"ST"),
@@ -19,24 +22,91 @@ impl fmt::Display for ProccessState {
     }
 }
 
+// what is known of one process in a job
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum ProcessStatus {
+    Running,
+    Stopped(Signal),
+    Exited(i32),
+    Signaled(Signal),
+}
+
+impl ProcessStatus {
+    pub fn done(self) -> bool {
+        matches!(self, ProcessStatus::Exited(_) | ProcessStatus::Signaled(_))
+    }
+
+    // the status a finished process leaves in $?
+    pub fn code(self) -> Option<i32> {
+        match self {
+            ProcessStatus::Exited(code) => Some(code),
+            ProcessStatus::Signaled(signal) | ProcessStatus::Stopped(signal) => Some(128 + signal as i32),
+            ProcessStatus::Running => None,
+        }
+    }
+}
+
+pub struct Process {
+    pub pid: i32,
+    pub status: ProcessStatus,
+}
+
+// what a change to one process did to its job
+pub enum JobChange {
+    Stopped {jid: u32, pgid: i32, signal: Signal},
+    // done, with the status of its last process
+    Done {jid: u32, pgid: i32, status: ProcessStatus},
+    Unchanged,
+}
+
 pub struct Job {
-    pub pids: Vec<i32>,
+    pub processes: Vec<Process>,
     pub pgid: i32,
     pub jid: u32,
     pub state: ProccessState,
     pub cmdline: String,
-
+    // the terminal modes it had when it stopped
+    pub modes: Option<Termios>,
+    // when it is a { ...; } group stopped part way, the pipe its last process,
+    // a copy of the shell, waits on for the status to go on with the group
+    pub rest: Option<File>,
+    // set while wait is waiting for it, so that its status is kept
+    pub waited: bool,
 }
 
 impl Job {
-    pub fn new(pids: &Vec<i32>, pgid: i32, jid: u32, state: ProccessState, cmdline: &str) -> Self {
-        Self {pids: pids.to_vec(), pgid: pgid, jid: jid, state: state, cmdline: cmdline.to_string()}
+    pub fn new(pids: &[i32], pgid: i32, jid: u32, state: ProccessState, cmdline: &str) -> Self {
+        let processes = pids.iter().map(|pid| Process {pid: *pid, status: ProcessStatus::Running}).collect();
+        Self {processes, pgid, jid, state, cmdline: cmdline.to_string(), modes: None, rest: None, waited: false}
+    }
+
+    pub fn contains(&self, pid: i32) -> bool {
+        self.processes.iter().any(|process| process.pid == pid)
+    }
+
+    // the signal that stopped it, if any of its processes is stopped
+    pub fn stop_signal(&self) -> Option<Signal> {
+        self.processes.iter().find_map(|process| match process.status {
+            ProcessStatus::Stopped(signal) => Some(signal),
+            _ => None,
+        })
+    }
+
+    // sets it going again as a foreground or background job
+    pub fn resume(&mut self, state: ProccessState) {
+        self.state = state;
+        for process in self.processes.iter_mut() {
+            if let ProcessStatus::Stopped(_) = process.status {
+                process.status = ProcessStatus::Running;
+            }
+        }
     }
 }
 
-impl fmt::Display for Job {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let result = write!(f,"[{}] ({}) ",self.jid,self.pgid);
+impl Job {
+    // marker is + for the current job, - for the previous one
+    fn write(&self, f: &mut fmt::Formatter<'_>, marker: &str) -> fmt::Result {
+        let result = write!(f,"[{}]{} ({}) ",self.jid,marker,self.pgid);
         if result == Err(std::fmt::Error) {
             return result;
         }
@@ -44,7 +114,6 @@ impl fmt::Display for Job {
             ProccessState::FG => write!(f,"Foreground "),
             ProccessState::BG => write!(f,"Running "),
             ProccessState::ST => write!(f,"Stopped "),
-            ProccessState::UNDEF =>  write!(f,"listjobs: Internal error: job[{}].state={} ",self.jid,self.state),
         };
         if result == Err(std::fmt::Error) {
             return result;
@@ -53,34 +122,155 @@ impl fmt::Display for Job {
     }
 }
 
+impl fmt::Display for Job {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.write(f,"")
+    }
+}
+
 pub struct Jobs {
     jobs: Vec<Job>,
     next_jid: u32,
+    // exit statuses of finished foreground or waited for jobs by process
+    // group, until they are asked for
+    statuses: BTreeMap<i32,i32>,
+    // processes behind $(...), <(...) and >(...), which are reaped but never
+    // reported, with whether the shell waits for their status
+    substitutions: BTreeMap<i32,bool>,
+    // jids of background and stopped jobs, the most recently started,
+    // stopped or sent to the background last
+    recent: Vec<u32>,
 }
 
 impl Jobs {
     pub const fn new() -> Self {
-        Self {jobs: Vec::new(),next_jid: 1}
+        Self {jobs: Vec::new(),next_jid: 1,statuses: BTreeMap::new(),substitutions: BTreeMap::new(),recent: Vec::new()}
     }
 
-    pub fn addjob(&mut self, pids: &Vec<i32>, pgid: i32, state: ProccessState, cmdline: &str) {
+    pub fn addjob(&mut self, pids: &[i32], pgid: i32, state: ProccessState, cmdline: &str) {
+       // one left by an earlier job with the same pgid isn't this one's
+       self.statuses.remove(&pgid);
+       if let ProccessState::BG = state {
+           self.recent.push(self.next_jid);
+       }
        self.jobs.push(Job::new(pids,pgid,self.next_jid,state,cmdline)); 
        self.next_jid += 1;
     }
 
-    pub fn delete_job(&mut self,pid: i32) -> Result<&str,&str> {
-        if pid < 1 {
-            return Err("Invalid PID");
+    // makes it the current job, as when it is stopped or sent to the background
+    pub fn touch(&mut self, jid: u32) {
+        self.recent.retain(|recent| *recent != jid);
+        self.recent.push(jid);
+    }
+
+    // stopped jobs come before running ones, so %+ and %- pick them first
+    fn ranked(&self) -> Vec<u32> {
+        let stopped = |jid: &u32| self.jobs.iter().any(|job| job.jid == *jid && matches!(job.state, ProccessState::ST));
+        let mut ranked: Vec<u32> = self.recent.iter().rev().copied().filter(stopped).collect();
+        ranked.extend(self.recent.iter().rev().copied().filter(|jid| !stopped(jid)));
+        ranked
+    }
+
+    pub fn current(&self) -> Option<u32> {
+        self.ranked().first().copied()
+    }
+
+    pub fn previous(&self) -> Option<u32> {
+        self.ranked().get(1).copied()
+    }
+
+    // the jid a job spec without its % names: n, +, %, -, a command prefix
+    // or ?, then a string the command contains
+    pub fn resolve(&self, spec: &str) -> Result<u32,String> {
+        let found = match spec {
+            "" | "+" | "%" => self.current(),
+            "-" => self.previous(),
+            _ => match spec.parse::<u32>() {
+                Ok(jid) => self.jobs.iter().find(|job| job.jid == jid).map(|job| job.jid),
+                Err(_) => {
+                    let matches: Vec<u32> = match spec.strip_prefix('?') {
+                        Some(text) => self.jobs.iter().filter(|job| job.cmdline.contains(text)).map(|job| job.jid).collect(),
+                        None => self.jobs.iter().filter(|job| job.cmdline.starts_with(spec)).map(|job| job.jid).collect(),
+                    };
+                    if matches.len() > 1 {
+                        return Err(format!("%{}: ambiguous job spec",spec));
+                    }
+                    matches.first().copied()
+                },
+            },
+        };
+        found.ok_or_else(|| format!("%{}: No such job",spec))
+    }
+
+    pub fn add_substitution(&mut self, pid: i32, wait: bool) {
+        self.substitutions.insert(pid,wait);
+    }
+
+    // records what happened to one process. a job is stopped as soon as any
+    // of its processes is and done only once all of them are
+    pub fn update(&mut self, pid: i32, status: ProcessStatus) -> JobChange {
+        if let Some(wait) = self.substitutions.get(&pid).copied() {
+            if status.done() {
+                self.substitutions.remove(&pid);
+                if wait {
+                    self.statuses.insert(pid,status.code().unwrap());
+                }
+            }
+            return JobChange::Unchanged;
+        }
+        let index = match self.jobs.iter().position(|job| job.contains(pid)) {
+            Some(index) => index,
+            None => return JobChange::Unchanged,
+        };
+
+        let job = &mut self.jobs[index];
+        for process in job.processes.iter_mut().filter(|process| process.pid == pid) {
+            process.status = status;
+        }
+
+        let waiting = job.processes.len() - 1;
+        if job.rest.is_some() && job.processes[..waiting].iter().all(|process| process.status.done()) {
+            let code = job.processes[waiting - 1].status.code().unwrap();
+            let _ = job.rest.take().unwrap().write_all(&code.to_ne_bytes());
+        }
+
+        if job.processes.iter().all(|process| process.status.done()) {
+            let last = job.processes.last().unwrap().status;
+            let job = self.jobs.remove(index);
+            if matches!(job.state, ProccessState::FG) || job.waited {
+                self.statuses.insert(job.pgid,last.code().unwrap());
+            }
+            self.recent.retain(|jid| *jid != job.jid);
+            self.set_next_jid();
+            return JobChange::Done {jid: job.jid, pgid: job.pgid, status: last};
         }
 
-        for i in 0..self.jobs.len() {
-            if *self.jobs[i].pids.last().unwrap() == pid {
-                self.jobs.remove(i);
-                self.set_next_jid();
-                return Ok("Successfully removed job");
+        if let ProcessStatus::Stopped(signal) = status {
+            if !matches!(job.state, ProccessState::ST) {
+                job.state = ProccessState::ST;
+                let (jid, pgid) = (job.jid,job.pgid);
+                self.touch(jid);
+                return JobChange::Stopped {jid, pgid, signal};
             }
         }
-        return Err("Invalid PID");
+        JobChange::Unchanged
+    }
+
+    // makes the copy of the shell that runs the rest of a stopped group the
+    // last process of its job. false if the job is gone
+    pub fn add_rest(&mut self, pgid: i32, pid: i32, pipe: File) -> bool {
+        match self.jobs.iter_mut().find(|job| job.pgid == pgid) {
+            Some(job) => {
+                job.processes.push(Process {pid, status: ProcessStatus::Running});
+                job.rest = Some(pipe);
+                true
+            },
+            None => false,
+        }
+    }
+
+    pub fn take_status(&mut self, pgid: i32) -> Option<i32> {
+        self.statuses.remove(&pgid)
     }
 
     fn set_next_jid(&mut self) {
@@ -93,39 +283,49 @@ impl Jobs {
         self.next_jid = max + 1;
     }
 
+    // the job led by, or containing, the process
     pub fn get_job_pid(&mut self, pid: i32) -> Option<&mut Job> {
-        for job in self.jobs.iter_mut() {
-            if job.pgid == pid {
-
-                return Some(job);
-            }
-        
-        }
-        return None;
+        self.jobs.iter_mut().find(|job| job.pgid == pid || job.contains(pid))
     }
 
     pub fn get_job_jid(&mut self, jid: u32) -> Option<&mut Job> {
-        if jid > self.next_jid || jid <= 0 {
-            return None;
-        } 
-        
-        return Some(&mut self.jobs[jid as usize - 1]);
+        self.jobs.iter_mut().find(|job| job.jid == jid)
+    }
+
+    // drops it from the table without touching its processes, which are
+    // still reaped but no longer reported
+    pub fn remove(&mut self, jid: u32) -> Option<Job> {
+        let index = self.jobs.iter().position(|job| job.jid == jid)?;
+        self.recent.retain(|recent| *recent != jid);
+        let job = self.jobs.remove(index);
+        self.statuses.remove(&job.pgid);
+        self.set_next_jid();
+        Some(job)
     }
-    
 
-    pub fn iter(&self) -> std::slice::Iter<Job> {
+    pub fn iter(&self) -> std::slice::Iter<'_,Job> {
         self.jobs.iter()
     }
-    pub fn iter_mut(&mut self) -> std::slice::IterMut<Job> {
+
+    pub fn iter_mut(&mut self) -> std::slice::IterMut<'_,Job> {
         self.jobs.iter_mut()
     }
 }
 
 impl fmt::Display for Jobs {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (current, previous) = (self.current(),self.previous());
         for job in self.jobs.iter() {
-            
-            let result = write!(f,"{}",job);
+            let marker = if Some(job.jid) == current {
+                "+"
+            }
+            else if Some(job.jid) == previous {
+                "-"
+            }
+            else {
+                " "
+            };
+            let result = job.write(f,marker).and_then(|_| writeln!(f));
 
             if result == Err(std::fmt::Error) {
                 return result;
@@ -134,3 +334,106 @@ impl fmt::Display for Jobs {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ProcessStatus::*;
+
+    #[test]
+    fn pipeline_done() {
+        let mut jobs = Jobs::new();
+        jobs.addjob(&[10,11,12],10,ProccessState::FG,"a | b | c");
+        assert!(matches!(jobs.update(12,Exited(0)),JobChange::Unchanged));
+        assert!(matches!(jobs.update(10,Signaled(Signal::SIGPIPE)),JobChange::Unchanged));
+        assert!(jobs.get_job_pid(11).is_some());
+        // the status is the last process's, whichever finishes last
+        assert!(matches!(jobs.update(11,Exited(3)),JobChange::Done {jid: 1, pgid: 10, status: Exited(0)}));
+        assert!(jobs.get_job_pid(10).is_none());
+        assert_eq!(jobs.take_status(10),Some(0));
+        assert_eq!(jobs.take_status(10),None);
+    }
+
+    #[test]
+    fn stopped() {
+        let mut jobs = Jobs::new();
+        jobs.addjob(&[20,21],20,ProccessState::FG,"a | b");
+        assert!(matches!(jobs.update(21,Stopped(Signal::SIGTSTP)),JobChange::Stopped {jid: 1, pgid: 20, signal: Signal::SIGTSTP}));
+        // only the first stop is news
+        assert!(matches!(jobs.update(20,Stopped(Signal::SIGTSTP)),JobChange::Unchanged));
+        let job = jobs.get_job_pid(20).unwrap();
+        assert!(matches!(job.state, ProccessState::ST));
+        assert_eq!(job.stop_signal(),Some(Signal::SIGTSTP));
+        assert_eq!(jobs.current(),Some(1));
+
+        jobs.get_job_pid(20).unwrap().resume(ProccessState::BG);
+        assert_eq!(jobs.get_job_pid(20).unwrap().stop_signal(),None);
+        assert!(matches!(jobs.update(20,Exited(0)),JobChange::Unchanged));
+        assert!(matches!(jobs.update(21,Signaled(Signal::SIGTERM)),JobChange::Done {status: Signaled(Signal::SIGTERM), ..}));
+        // no one waits on a background job
+        assert_eq!(jobs.take_status(20),None);
+        assert_eq!(jobs.current(),None);
+    }
+
+    #[test]
+    fn waited() {
+        let mut jobs = Jobs::new();
+        jobs.addjob(&[30],30,ProccessState::BG,"a");
+        jobs.get_job_pid(30).unwrap().waited = true;
+        jobs.update(30,Exited(2));
+        assert_eq!(jobs.take_status(30),Some(2));
+    }
+
+    #[test]
+    fn substitutions() {
+        let mut jobs = Jobs::new();
+        jobs.add_substitution(40,true);
+        jobs.add_substitution(41,false);
+        assert!(matches!(jobs.update(40,Stopped(Signal::SIGTTOU)),JobChange::Unchanged));
+        assert_eq!(jobs.take_status(40),None);
+        assert!(matches!(jobs.update(40,Exited(4)),JobChange::Unchanged));
+        assert!(matches!(jobs.update(41,Exited(5)),JobChange::Unchanged));
+        assert_eq!(jobs.take_status(40),Some(4));
+        assert_eq!(jobs.take_status(41),None);
+        assert!(jobs.iter().next().is_none());
+    }
+
+    fn three_jobs() -> Jobs {
+        let mut jobs = Jobs::new();
+        jobs.addjob(&[50],50,ProccessState::BG,"sleep 10");
+        jobs.addjob(&[51],51,ProccessState::BG,"sleep 20");
+        jobs.addjob(&[52],52,ProccessState::BG,"cat file");
+        jobs
+    }
+
+    #[test]
+    fn ranking() {
+        let mut jobs = three_jobs();
+        assert_eq!((jobs.current(),jobs.previous()),(Some(3),Some(2)));
+        // a stopped job comes first, however old
+        jobs.update(50,Stopped(Signal::SIGSTOP));
+        assert_eq!((jobs.current(),jobs.previous()),(Some(1),Some(3)));
+        jobs.get_job_pid(50).unwrap().resume(ProccessState::BG);
+        jobs.touch(2);
+        assert_eq!((jobs.current(),jobs.previous()),(Some(2),Some(1)));
+        jobs.remove(2);
+        assert_eq!((jobs.current(),jobs.previous()),(Some(1),Some(3)));
+    }
+
+    #[test]
+    fn resolve() {
+        let jobs = three_jobs();
+        assert_eq!(jobs.resolve("2"),Ok(2));
+        assert_eq!(jobs.resolve(""),Ok(3));
+        assert_eq!(jobs.resolve("+"),Ok(3));
+        assert_eq!(jobs.resolve("%"),Ok(3));
+        assert_eq!(jobs.resolve("-"),Ok(2));
+        assert_eq!(jobs.resolve("cat"),Ok(3));
+        assert_eq!(jobs.resolve("?20"),Ok(2));
+        assert_eq!(jobs.resolve("sleep"),Err("%sleep: ambiguous job spec".to_string()));
+        assert_eq!(jobs.resolve("?e"),Err("%?e: ambiguous job spec".to_string()));
+        assert_eq!(jobs.resolve("4"),Err("%4: No such job".to_string()));
+        assert_eq!(jobs.resolve("vi"),Err("%vi: No such job".to_string()));
+        assert_eq!(Jobs::new().resolve("+"),Err("%+: No such job".to_string()));
+    }
+}
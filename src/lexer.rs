@@ -0,0 +1,1003 @@
+use crate::parser;
+use std::fmt;
+
+#[derive(Debug,Clone,PartialEq)]
+pub enum WordPart {
+    Literal(String),
+    SingleQuoted(String),
+    DoubleQuoted(Vec<WordPart>),
+    Escaped(char),
+    Param(Param),
+    CommandSub(String),
+    // <(...), or >(...) when set
+    ProcessSub(bool,String),
+    Arith(Vec<WordPart>),
+}
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum Anchor {
+    Anywhere,
+    Start,
+    End,
+}
+
+// the operator in ${NAME...}, the bools say whether there was a colon
+// for the default value forms and whether the longest match or all
+// characters are meant for the pattern ones
+#[derive(Debug,Clone,PartialEq)]
+pub enum ParamOp {
+    Plain,
+    Length,
+    Default(bool,Word),
+    Assign(bool,Word),
+    Error(bool,Word),
+    Alternative(bool,Word),
+    RemovePrefix(bool,Word),
+    RemoveSuffix(bool,Word),
+    Replace {all: bool, anchor: Anchor, pattern: Word, replacement: Word},
+    Substring(Word,Option<Word>),
+    Upper(bool,Word),
+    Lower(bool,Word),
+}
+
+#[derive(Debug,Clone,PartialEq)]
+pub struct Param {
+    pub name: String,
+    pub op: ParamOp,
+}
+
+impl fmt::Display for Param {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let colon = |colon: &bool| if *colon { ":" } else { "" };
+        let double = |double: &bool, c: &str| if *double { c.repeat(2) } else { c.to_string() };
+        match &self.op {
+            ParamOp::Plain => write!(f,"${{{}}}",self.name),
+            ParamOp::Length => write!(f,"${{#{}}}",self.name),
+            ParamOp::Default(c, word) => write!(f,"${{{}{}-{}}}",self.name,colon(c),word),
+            ParamOp::Assign(c, word) => write!(f,"${{{}{}={}}}",self.name,colon(c),word),
+            ParamOp::Error(c, word) => write!(f,"${{{}{}?{}}}",self.name,colon(c),word),
+            ParamOp::Alternative(c, word) => write!(f,"${{{}{}+{}}}",self.name,colon(c),word),
+            ParamOp::RemovePrefix(longest, word) => write!(f,"${{{}{}{}}}",self.name,double(longest,"#"),word),
+            ParamOp::RemoveSuffix(longest, word) => write!(f,"${{{}{}{}}}",self.name,double(longest,"%"),word),
+            ParamOp::Replace {all, anchor, pattern, replacement} => {
+                let anchor = match anchor {
+                    Anchor::Anywhere => "",
+                    Anchor::Start => "#",
+                    Anchor::End => "%",
+                };
+                write!(f,"${{{}{}{}{}/{}}}",self.name,double(all,"/"),anchor,pattern,replacement)
+            },
+            ParamOp::Substring(offset, None) => write!(f,"${{{}:{}}}",self.name,offset),
+            ParamOp::Substring(offset, Some(length)) => write!(f,"${{{}:{}:{}}}",self.name,offset,length),
+            ParamOp::Upper(all, word) => write!(f,"${{{}{}{}}}",self.name,double(all,"^"),word),
+            ParamOp::Lower(all, word) => write!(f,"${{{}{}{}}}",self.name,double(all,","),word),
+        }
+    }
+}
+
+#[derive(Debug,Clone,PartialEq,Default)]
+pub struct Word {
+    pub parts: Vec<WordPart>,
+}
+
+impl Word {
+    // the text of a word that has no quoting at all
+    pub fn literal(&self) -> Option<&str> {
+        match self.parts.as_slice() {
+            [WordPart::Literal(text)] => Some(text),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for WordPart {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WordPart::Literal(text) => write!(f,"{}",text),
+            WordPart::SingleQuoted(text) => write!(f,"'{}'",text),
+            WordPart::DoubleQuoted(parts) => {
+                write!(f,"\"")?;
+                for part in parts.iter() {
+                    write!(f,"{}",part)?;
+                }
+                write!(f,"\"")
+            },
+            WordPart::Escaped(c) => write!(f,"\\{}",c),
+            WordPart::Param(param) => write!(f,"{}",param),
+            WordPart::CommandSub(text) => write!(f,"$({})",text),
+            WordPart::ProcessSub(output, text) => write!(f,"{}({})",if *output { ">" } else { "<" },text),
+            WordPart::Arith(parts) => {
+                write!(f,"$((")?;
+                for part in parts.iter() {
+                    write!(f,"{}",part)?;
+                }
+                write!(f,"))")
+            },
+        }
+    }
+}
+
+impl fmt::Display for Word {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for part in self.parts.iter() {
+            write!(f,"{}",part)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug,Clone,PartialEq)]
+pub enum Token {
+    Word(Word),
+    Arith(Vec<WordPart>),
+    Pipe,
+    OrIf,
+    Amp,
+    AndIf,
+    Semi,
+    DSemi,
+    SemiAmp,
+    DSemiAmp,
+    LParen,
+    RParen,
+    // the digits right before a redirection operator
+    IoNumber(i32),
+    Less,
+    Great,
+    DGreat,
+    DLess,
+    DLessDash,
+    TLess,
+    LessAnd,
+    GreatAnd,
+    LessGreat,
+    Clobber,
+    AndGreat,
+    AndDGreat,
+    PipeAnd,
+    Newline,
+    Eof,
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Word(word) => write!(f,"{}",word),
+            Token::Arith(parts) => {
+                write!(f,"((")?;
+                for part in parts.iter() {
+                    write!(f,"{}",part)?;
+                }
+                write!(f,"))")
+            },
+            Token::Pipe => write!(f,"|"),
+            Token::OrIf => write!(f,"||"),
+            Token::Amp => write!(f,"&"),
+            Token::AndIf => write!(f,"&&"),
+            Token::Semi => write!(f,";"),
+            Token::DSemi => write!(f,";;"),
+            Token::SemiAmp => write!(f,";&"),
+            Token::DSemiAmp => write!(f,";;&"),
+            Token::LParen => write!(f,"("),
+            Token::RParen => write!(f,")"),
+            Token::Less => write!(f,"<"),
+            Token::Great => write!(f,">"),
+            Token::DGreat => write!(f,">>"),
+            Token::IoNumber(fd) => write!(f,"{}",fd),
+            Token::DLess => write!(f,"<<"),
+            Token::DLessDash => write!(f,"<<-"),
+            Token::TLess => write!(f,"<<<"),
+            Token::LessAnd => write!(f,"<&"),
+            Token::GreatAnd => write!(f,">&"),
+            Token::LessGreat => write!(f,"<>"),
+            Token::Clobber => write!(f,">|"),
+            Token::AndGreat => write!(f,"&>"),
+            Token::AndDGreat => write!(f,"&>>"),
+            Token::PipeAnd => write!(f,"|&"),
+            Token::Newline => write!(f,"newline"),
+            Token::Eof => write!(f,"end of file"),
+        }
+    }
+}
+
+#[derive(Debug,PartialEq)]
+pub enum SyntaxError {
+    Incomplete,
+    Unexpected(String),
+    BadSubstitution(String),
+}
+
+impl fmt::Display for SyntaxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SyntaxError::Incomplete => write!(f,"syntax error: unexpected end of file"),
+            SyntaxError::Unexpected(token) => write!(f,"syntax error near unexpected token `{}'",token),
+            SyntaxError::BadSubstitution(text) => write!(f,"{}: bad substitution",text),
+        }
+    }
+}
+
+fn is_metachar(c: char) -> bool {
+    matches!(c, ' ' | '\t' | '\r' | '\n' | '|' | '&' | ';' | '(' | ')' | '<' | '>')
+}
+
+fn is_name_start(c: char) -> bool {
+    c == '_' || c.is_ascii_alphabetic()
+}
+
+fn is_name_char(c: char) -> bool {
+    c == '_' || c.is_ascii_alphanumeric()
+}
+
+fn push_literal(parts: &mut Vec<WordPart>, literal: &mut String) {
+    if !literal.is_empty() {
+        parts.push(WordPart::Literal(std::mem::take(literal)));
+    }
+}
+
+// the line that ends a here-document, and whether any of it was quoted,
+// which keeps the body from being expanded
+fn heredoc_delimiter(word: &Word) -> (String,bool) {
+    let mut text = String::new();
+    let mut quoted = false;
+    for part in word.parts.iter() {
+        match part {
+            WordPart::Literal(literal) => text.push_str(literal),
+            WordPart::SingleQuoted(literal) => {
+                text.push_str(literal);
+                quoted = true;
+            },
+            WordPart::Escaped(c) => {
+                text.push(*c);
+                quoted = true;
+            },
+            WordPart::DoubleQuoted(parts) => {
+                for part in parts.iter() {
+                    text.push_str(&part.to_string());
+                }
+                quoted = true;
+            },
+            part => text.push_str(&part.to_string()),
+        }
+    }
+    (text,quoted)
+}
+
+pub struct Lexer {
+    chars: Vec<char>,
+    pos: usize,
+    // where the last token read begins
+    token_start: usize,
+    // how much of the input here-document bodies took away
+    removed: usize,
+}
+
+impl Lexer {
+    pub fn new(input: &str) -> Self {
+        Self {chars: input.chars().collect(), pos: 0, token_start: 0, removed: 0}
+    }
+
+    pub fn at(chars: &[char], pos: usize) -> Self {
+        Self {chars: chars.to_vec(), pos, token_start: pos, removed: 0}
+    }
+
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+
+    // the position in the input as it was given, here-documents included
+    pub fn source_position(&self) -> usize {
+        self.pos + self.removed
+    }
+
+    pub fn token_start(&self) -> usize {
+        self.token_start
+    }
+
+    // the source text between two positions
+    pub fn text(&self, start: usize, end: usize) -> String {
+        self.chars[start..end].iter().collect::<String>().trim().to_string()
+    }
+
+    fn peek_char(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn peek_char_at(&self, offset: usize) -> Option<char> {
+        self.chars.get(self.pos + offset).copied()
+    }
+
+    // skips a backslash-newline pair. one that ends the input means the
+    // command carries on in the next line
+    fn line_continuation(&mut self) -> Result<bool,SyntaxError> {
+        if self.peek_char() != Some('\\') || self.peek_char_at(1) != Some('\n') {
+            return Ok(false);
+        }
+        self.pos += 2;
+        if self.pos >= self.chars.len() {
+            return Err(SyntaxError::Incomplete);
+        }
+        Ok(true)
+    }
+
+    fn skip_blanks(&mut self) -> Result<(),SyntaxError> {
+        while let Some(c) = self.peek_char() {
+            match c {
+                ' ' | '\t' | '\r' => self.pos += 1,
+                '#' => {
+                    while self.peek_char().is_some_and(|c| c != '\n') {
+                        self.pos += 1;
+                    }
+                },
+                '\\' => {
+                    if !self.line_continuation()? {
+                        break;
+                    }
+                },
+                _ => break,
+            }
+        }
+        Ok(())
+    }
+
+    pub fn next_token(&mut self) -> Result<Token,SyntaxError> {
+        self.skip_blanks()?;
+        self.token_start = self.pos;
+
+        let c = match self.peek_char() {
+            Some(c) => c,
+            None => return Ok(Token::Eof),
+        };
+        let next = self.peek_char_at(1);
+
+        if let Some(fd) = self.read_io_number() {
+            return Ok(Token::IoNumber(fd));
+        }
+
+        let (token, len) = match (c, next) {
+            ('\n', _) => (Token::Newline, 1),
+            ('|', Some('|')) => (Token::OrIf, 2),
+            ('|', Some('&')) => (Token::PipeAnd, 2),
+            ('|', _) => (Token::Pipe, 1),
+            ('&', Some('&')) => (Token::AndIf, 2),
+            ('&', Some('>')) if self.peek_char_at(2) == Some('>') => (Token::AndDGreat, 3),
+            ('&', Some('>')) => (Token::AndGreat, 2),
+            ('&', _) => (Token::Amp, 1),
+            (';', Some(';')) if self.peek_char_at(2) == Some('&') => (Token::DSemiAmp, 3),
+            (';', Some(';')) => (Token::DSemi, 2),
+            (';', Some('&')) => (Token::SemiAmp, 2),
+            (';', _) => (Token::Semi, 1),
+            ('(', Some('(')) => {
+                self.pos += 2;
+                if let Some(parts) = self.read_arithmetic()? {
+                    return Ok(Token::Arith(parts));
+                }
+                self.pos -= 2;
+                (Token::LParen, 1)
+            },
+            ('(', _) => (Token::LParen, 1),
+            (')', _) => (Token::RParen, 1),
+            ('<' | '>', Some('(')) => return self.read_word().map(Token::Word),
+            ('<', Some('<')) => match self.peek_char_at(2) {
+                Some('<') => (Token::TLess, 3),
+                Some('-') => (Token::DLessDash, 3),
+                _ => (Token::DLess, 2),
+            },
+            ('<', Some('&')) => (Token::LessAnd, 2),
+            ('<', Some('>')) => (Token::LessGreat, 2),
+            ('<', _) => (Token::Less, 1),
+            ('>', Some('>')) => (Token::DGreat, 2),
+            ('>', Some('&')) => (Token::GreatAnd, 2),
+            ('>', Some('|')) => (Token::Clobber, 2),
+            ('>', _) => (Token::Great, 1),
+            _ => return self.read_word().map(Token::Word),
+        };
+        self.pos += len;
+        Ok(token)
+    }
+
+    // digits directly followed by < or > name the descriptor to redirect
+    fn read_io_number(&mut self) -> Option<i32> {
+        let mut len = 0;
+        while self.peek_char_at(len).is_some_and(|c| c.is_ascii_digit()) {
+            len += 1;
+        }
+        if len == 0 || !matches!(self.peek_char_at(len), Some('<' | '>')) {
+            return None;
+        }
+        let digits: String = self.chars[self.pos..self.pos + len].iter().collect();
+        let fd = digits.parse().ok()?;
+        self.pos += len;
+        Some(fd)
+    }
+
+    // reads the body of a here-document, which starts on the line after the
+    // current one, and takes it out of the input so the parser carries on
+    // with the rest of the command line
+    pub fn read_heredoc(&mut self, delimiter: &Word, strip_tabs: bool) -> Result<Word,SyntaxError> {
+        let (delimiter, quoted) = heredoc_delimiter(delimiter);
+        let len = self.chars.len();
+        let line_end = |from: usize| self.chars[from..].iter().position(|c| *c == '\n').map(|i| from + i);
+
+        let start = match line_end(self.pos) {
+            Some(end) => end + 1,
+            None => return Err(SyntaxError::Incomplete),
+        };
+        let mut body = String::new();
+        let mut line_start = start;
+        let end = loop {
+            if line_start >= len {
+                return Err(SyntaxError::Incomplete);
+            }
+            let end = line_end(line_start);
+            let mut line: String = self.chars[line_start..end.unwrap_or(len)].iter().collect();
+            if strip_tabs {
+                line = line.trim_start_matches('\t').to_string();
+            }
+            let next = end.map_or(len,|end| end + 1);
+            if line == delimiter {
+                break next;
+            }
+            if end.is_none() {
+                return Err(SyntaxError::Incomplete);
+            }
+            body.push_str(&line);
+            body.push('\n');
+            line_start = next;
+        };
+        self.chars.drain(start..end);
+        self.removed += end - start;
+
+        if quoted {
+            return Ok(Word {parts: vec![WordPart::SingleQuoted(body)]});
+        }
+        let parts = Lexer::new(&body).read_expandable(false)?;
+        Ok(Word {parts: vec![WordPart::DoubleQuoted(parts)]})
+    }
+
+    fn read_word(&mut self) -> Result<Word,SyntaxError> {
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+
+        while let Some(c) = self.peek_char() {
+            if matches!(c, '<' | '>') && self.peek_char_at(1) == Some('(') {
+                push_literal(&mut parts,&mut literal);
+                let start = self.pos + 2;
+                let end = parser::substitution_end(&self.chars,start)?;
+                self.pos = end;
+                let text = self.chars[start..end - 1].iter().collect();
+                parts.push(WordPart::ProcessSub(c == '>',text));
+                continue;
+            }
+            if is_metachar(c) {
+                break;
+            }
+            if self.line_continuation()? {
+                continue;
+            }
+            self.pos += 1;
+
+            match c {
+                '\\' => match self.peek_char() {
+                    Some(next) => {
+                        push_literal(&mut parts,&mut literal);
+                        parts.push(WordPart::Escaped(next));
+                        self.pos += 1;
+                    },
+                    None => literal.push(c),
+                },
+                '\'' => {
+                    push_literal(&mut parts,&mut literal);
+                    let mut quoted = String::new();
+                    loop {
+                        match self.peek_char() {
+                            Some('\'') => break,
+                            Some(c) => quoted.push(c),
+                            None => return Err(SyntaxError::Incomplete),
+                        }
+                        self.pos += 1;
+                    }
+                    self.pos += 1;
+                    parts.push(WordPart::SingleQuoted(quoted));
+                },
+                '"' => {
+                    push_literal(&mut parts,&mut literal);
+                    let quoted = self.read_double_quoted()?;
+                    parts.push(WordPart::DoubleQuoted(quoted));
+                },
+                '`' => {
+                    push_literal(&mut parts,&mut literal);
+                    parts.push(self.read_backquoted()?);
+                },
+                '$' => match self.read_dollar()? {
+                    Some(part) => {
+                        push_literal(&mut parts,&mut literal);
+                        parts.push(part);
+                    },
+                    None => literal.push(c),
+                },
+                _ => literal.push(c),
+            }
+        }
+
+        push_literal(&mut parts,&mut literal);
+        Ok(Word {parts})
+    }
+
+    // reads up to the closing quote, the opening one is already consumed
+    fn read_double_quoted(&mut self) -> Result<Vec<WordPart>,SyntaxError> {
+        self.read_expandable(true)
+    }
+
+    // the inside of double quotes, or with quote unset a here-document body,
+    // where " is an ordinary character and the input ends it
+    fn read_expandable(&mut self, quote: bool) -> Result<Vec<WordPart>,SyntaxError> {
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+
+        loop {
+            let c = match self.peek_char() {
+                Some(c) => c,
+                None if quote => return Err(SyntaxError::Incomplete),
+                None => break,
+            };
+            self.pos += 1;
+
+            match c {
+                '"' if quote => break,
+                '\\' => match self.peek_char() {
+                    Some('\n') => self.pos += 1,
+                    Some(next) if matches!(next, '$' | '`' | '\\') || (quote && next == '"') => {
+                        literal.push(next);
+                        self.pos += 1;
+                    },
+                    Some(_) => literal.push(c),
+                    None => return Err(SyntaxError::Incomplete),
+                },
+                '$' => match self.read_dollar()? {
+                    Some(part) => {
+                        push_literal(&mut parts,&mut literal);
+                        parts.push(part);
+                    },
+                    None => literal.push(c),
+                },
+                '`' => {
+                    push_literal(&mut parts,&mut literal);
+                    parts.push(self.read_backquoted()?);
+                },
+                _ => literal.push(c),
+            }
+        }
+
+        push_literal(&mut parts,&mut literal);
+        Ok(parts)
+    }
+
+    // reads an old style `command` substitution, the opening backquote is
+    // already consumed. backslashes only escape $, ` and \ in here
+    fn read_backquoted(&mut self) -> Result<WordPart,SyntaxError> {
+        let mut text = String::new();
+
+        loop {
+            let c = match self.peek_char() {
+                Some(c) => c,
+                None => return Err(SyntaxError::Incomplete),
+            };
+            self.pos += 1;
+
+            match c {
+                '`' => break,
+                '\\' => match self.peek_char() {
+                    Some(next) if matches!(next, '$' | '`' | '\\') => {
+                        text.push(next);
+                        self.pos += 1;
+                    },
+                    _ => text.push(c),
+                },
+                _ => text.push(c),
+            }
+        }
+
+        Ok(WordPart::CommandSub(text))
+    }
+
+    // reads a word inside ${...} up to one of the stop characters, which is
+    // consumed and returned. braces nest and quotes work as they do outside
+    fn read_brace_word(&mut self, stops: &[char]) -> Result<(Word,char),SyntaxError> {
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+        let mut depth = 0;
+
+        let stop = loop {
+            let c = match self.peek_char() {
+                Some(c) => c,
+                None => return Err(SyntaxError::Incomplete),
+            };
+            if depth == 0 && stops.contains(&c) {
+                self.pos += 1;
+                break c;
+            }
+            if self.line_continuation()? {
+                continue;
+            }
+            self.pos += 1;
+
+            match c {
+                '{' => {
+                    depth += 1;
+                    literal.push(c);
+                },
+                '}' => {
+                    depth -= 1;
+                    literal.push(c);
+                },
+                '\\' => match self.peek_char() {
+                    Some(next) => {
+                        push_literal(&mut parts,&mut literal);
+                        parts.push(WordPart::Escaped(next));
+                        self.pos += 1;
+                    },
+                    None => return Err(SyntaxError::Incomplete),
+                },
+                '\'' => {
+                    push_literal(&mut parts,&mut literal);
+                    let mut quoted = String::new();
+                    loop {
+                        match self.peek_char() {
+                            Some('\'') => break,
+                            Some(c) => quoted.push(c),
+                            None => return Err(SyntaxError::Incomplete),
+                        }
+                        self.pos += 1;
+                    }
+                    self.pos += 1;
+                    parts.push(WordPart::SingleQuoted(quoted));
+                },
+                '"' => {
+                    push_literal(&mut parts,&mut literal);
+                    let quoted = self.read_double_quoted()?;
+                    parts.push(WordPart::DoubleQuoted(quoted));
+                },
+                '$' => match self.read_dollar()? {
+                    Some(part) => {
+                        push_literal(&mut parts,&mut literal);
+                        parts.push(part);
+                    },
+                    None => literal.push(c),
+                },
+                '`' => {
+                    push_literal(&mut parts,&mut literal);
+                    parts.push(self.read_backquoted()?);
+                },
+                _ => literal.push(c),
+            }
+        };
+
+        push_literal(&mut parts,&mut literal);
+        Ok((Word {parts},stop))
+    }
+
+    fn bad_substitution(&mut self, start: usize) -> SyntaxError {
+        while self.peek_char().is_some_and(|c| c != '}') {
+            self.pos += 1;
+        }
+        let end = (self.pos + 1).min(self.chars.len());
+        self.pos = end;
+        let text: String = self.chars[start..end].iter().collect();
+        SyntaxError::BadSubstitution(format!("${{{}",text))
+    }
+
+    // reads what follows ${, up to and including the closing brace
+    fn read_braced_param(&mut self) -> Result<Param,SyntaxError> {
+        let start = self.pos;
+
+        let length = self.peek_char() == Some('#') && self.peek_char_at(1).is_some_and(|c| c != '}');
+        if length {
+            self.pos += 1;
+        }
+
+        let mut name = String::new();
+        match self.peek_char() {
+            Some(c) if is_name_start(c) => {
+                while let Some(c) = self.peek_char().filter(|c| is_name_char(*c)) {
+                    name.push(c);
+                    self.pos += 1;
+                }
+            },
+            Some(c) if c.is_ascii_digit() => {
+                while let Some(c) = self.peek_char().filter(|c| c.is_ascii_digit()) {
+                    name.push(c);
+                    self.pos += 1;
+                }
+            },
+            Some(c) if matches!(c, '@' | '*' | '#' | '?' | '$' | '!' | '-') => {
+                name.push(c);
+                self.pos += 1;
+            },
+            Some(_) => return Err(self.bad_substitution(start)),
+            None => return Err(SyntaxError::Incomplete),
+        }
+
+        let c = match self.peek_char() {
+            Some(c) => c,
+            None => return Err(SyntaxError::Incomplete),
+        };
+        if length {
+            if c != '}' {
+                return Err(self.bad_substitution(start));
+            }
+            self.pos += 1;
+            return Ok(Param {name, op: ParamOp::Length});
+        }
+        self.pos += 1;
+
+        let colon = c == ':' && matches!(self.peek_char(), Some('-' | '=' | '?' | '+'));
+        let c = if colon {
+            self.pos += 1;
+            self.chars[self.pos - 1]
+        }
+        else {
+            c
+        };
+        let doubled = |lexer: &mut Self| {
+            let doubled = lexer.peek_char() == Some(c);
+            if doubled {
+                lexer.pos += 1;
+            }
+            doubled
+        };
+
+        let op = match c {
+            '}' => ParamOp::Plain,
+            '-' => ParamOp::Default(colon,self.read_brace_word(&['}'])?.0),
+            '=' => ParamOp::Assign(colon,self.read_brace_word(&['}'])?.0),
+            '?' => ParamOp::Error(colon,self.read_brace_word(&['}'])?.0),
+            '+' => ParamOp::Alternative(colon,self.read_brace_word(&['}'])?.0),
+            '#' => {
+                let longest = doubled(self);
+                ParamOp::RemovePrefix(longest,self.read_brace_word(&['}'])?.0)
+            },
+            '%' => {
+                let longest = doubled(self);
+                ParamOp::RemoveSuffix(longest,self.read_brace_word(&['}'])?.0)
+            },
+            '^' => {
+                let all = doubled(self);
+                ParamOp::Upper(all,self.read_brace_word(&['}'])?.0)
+            },
+            ',' => {
+                let all = doubled(self);
+                ParamOp::Lower(all,self.read_brace_word(&['}'])?.0)
+            },
+            '/' => {
+                let all = doubled(self);
+                let anchor = match self.peek_char() {
+                    Some('#') if !all => Anchor::Start,
+                    Some('%') if !all => Anchor::End,
+                    _ => Anchor::Anywhere,
+                };
+                if anchor != Anchor::Anywhere {
+                    self.pos += 1;
+                }
+                let (pattern, stop) = self.read_brace_word(&['/','}'])?;
+                let replacement = if stop == '/' {
+                    self.read_brace_word(&['}'])?.0
+                }
+                else {
+                    Word::default()
+                };
+                ParamOp::Replace {all, anchor, pattern, replacement}
+            },
+            ':' => {
+                let (offset, stop) = self.read_brace_word(&[':','}'])?;
+                let length = if stop == ':' {
+                    Some(self.read_brace_word(&['}'])?.0)
+                }
+                else {
+                    None
+                };
+                ParamOp::Substring(offset,length)
+            },
+            _ => {
+                self.pos -= 1;
+                return Err(self.bad_substitution(start));
+            },
+        };
+
+        Ok(Param {name, op})
+    }
+
+    // reads the body of $((...)) or ((...)) up to the closing )). if the
+    // parentheses don't close that way nothing is consumed, as it is then
+    // a command substitution or subshell that starts with another one
+    fn read_arithmetic(&mut self) -> Result<Option<Vec<WordPart>>,SyntaxError> {
+        let start = self.pos;
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+        let mut depth = 0;
+
+        loop {
+            let c = match self.peek_char() {
+                Some(c) => c,
+                None => return Err(SyntaxError::Incomplete),
+            };
+            self.pos += 1;
+
+            match c {
+                '(' => {
+                    depth += 1;
+                    literal.push(c);
+                },
+                ')' if depth > 0 => {
+                    depth -= 1;
+                    literal.push(c);
+                },
+                ')' => {
+                    if self.peek_char() == Some(')') {
+                        self.pos += 1;
+                        break;
+                    }
+                    self.pos = start;
+                    return Ok(None);
+                },
+                '\\' => match self.peek_char() {
+                    Some('\n') => self.pos += 1,
+                    Some(next) if matches!(next, '$' | '`' | '"' | '\\') => {
+                        literal.push(next);
+                        self.pos += 1;
+                    },
+                    _ => literal.push(c),
+                },
+                '"' => {
+                    push_literal(&mut parts,&mut literal);
+                    let quoted = self.read_double_quoted()?;
+                    parts.push(WordPart::DoubleQuoted(quoted));
+                },
+                '$' => match self.read_dollar()? {
+                    Some(part) => {
+                        push_literal(&mut parts,&mut literal);
+                        parts.push(part);
+                    },
+                    None => literal.push(c),
+                },
+                '`' => {
+                    push_literal(&mut parts,&mut literal);
+                    parts.push(self.read_backquoted()?);
+                },
+                _ => literal.push(c),
+            }
+        }
+
+        push_literal(&mut parts,&mut literal);
+        Ok(Some(parts))
+    }
+
+    // reads what follows a $, which has already been consumed. a $ that does
+    // not start an expansion is literal
+    fn read_dollar(&mut self) -> Result<Option<WordPart>,SyntaxError> {
+        match self.peek_char() {
+            Some('(') if self.peek_char_at(1) == Some('(') => {
+                self.pos += 2;
+                if let Some(parts) = self.read_arithmetic()? {
+                    return Ok(Some(WordPart::Arith(parts)));
+                }
+                self.pos -= 1;
+                let start = self.pos;
+                let end = parser::substitution_end(&self.chars,start)?;
+                self.pos = end;
+                let text = self.chars[start..end - 1].iter().collect();
+                Ok(Some(WordPart::CommandSub(text)))
+            },
+            Some('(') => {
+                let start = self.pos + 1;
+                let end = parser::substitution_end(&self.chars,start)?;
+                self.pos = end;
+                let text = self.chars[start..end - 1].iter().collect();
+                Ok(Some(WordPart::CommandSub(text)))
+            },
+            Some('{') => {
+                self.pos += 1;
+                self.read_braced_param().map(|param| Some(WordPart::Param(param)))
+            },
+            Some(c) if is_name_start(c) => {
+                let mut name = String::new();
+                while let Some(c) = self.peek_char().filter(|c| is_name_char(*c)) {
+                    name.push(c);
+                    self.pos += 1;
+                }
+                Ok(Some(WordPart::Param(Param {name, op: ParamOp::Plain})))
+            },
+            // positional and special parameters are a single character
+            Some(c) if c.is_ascii_digit() || matches!(c, '#' | '@' | '*' | '?') => {
+                self.pos += 1;
+                Ok(Some(WordPart::Param(Param {name: c.to_string(), op: ParamOp::Plain})))
+            },
+            _ => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(input: &str) -> Vec<Token> {
+        let mut lexer = Lexer::new(input);
+        let mut tokens = Vec::new();
+        loop {
+            match lexer.next_token().unwrap() {
+                Token::Eof => return tokens,
+                token => tokens.push(token),
+            }
+        }
+    }
+
+    fn word(text: &str) -> Token {
+        Token::Word(Word {parts: vec![WordPart::Literal(text.to_string())]})
+    }
+
+    #[test]
+    fn operators() {
+        assert_eq!(tokens("a|b&&c||d;e&"),[word("a"),Token::Pipe,word("b"),Token::AndIf,word("c"),Token::OrIf,word("d"),Token::Semi,word("e"),Token::Amp]);
+        assert_eq!(tokens("a |& b\n"),[word("a"),Token::PipeAnd,word("b"),Token::Newline]);
+        assert_eq!(tokens("( a )"),[Token::LParen,word("a"),Token::RParen]);
+    }
+
+    #[test]
+    fn redirections() {
+        assert_eq!(tokens("a >out 2>>err <in"),[word("a"),Token::Great,word("out"),Token::IoNumber(2),Token::DGreat,word("err"),Token::Less,word("in")]);
+        assert_eq!(tokens("a 2>&1 >|f &>g &>>h <>i"),[word("a"),Token::IoNumber(2),Token::GreatAnd,word("1"),Token::Clobber,word("f"),Token::AndGreat,word("g"),Token::AndDGreat,word("h"),Token::LessGreat,word("i")]);
+        // digits only count as a descriptor right before the operator
+        assert_eq!(tokens("echo 2 >f"),[word("echo"),word("2"),Token::Great,word("f")]);
+        assert_eq!(tokens("a2>f"),[word("a2"),Token::Great,word("f")]);
+    }
+
+    #[test]
+    fn words() {
+        assert_eq!(tokens("  echo   hello\tworld "),[word("echo"),word("hello"),word("world")]);
+        assert_eq!(tokens("echo 'a b'"),[word("echo"),Token::Word(Word {parts: vec![WordPart::SingleQuoted("a b".to_string())]})]);
+        assert_eq!(tokens("echo a # comment"),[word("echo"),word("a")]);
+        assert_eq!(tokens("echo a#b"),[word("echo"),word("a#b")]);
+    }
+
+    fn parts(input: &str) -> Vec<WordPart> {
+        match Lexer::new(input).next_token().unwrap() {
+            Token::Word(word) => word.parts,
+            token => panic!("not a word: {:?}",token),
+        }
+    }
+
+    fn param(name: &str) -> WordPart {
+        WordPart::Param(Param {name: name.to_string(), op: ParamOp::Plain})
+    }
+
+    #[test]
+    fn double_quotes() {
+        assert_eq!(parts("\"a $x b\""),[WordPart::DoubleQuoted(vec![WordPart::Literal("a ".to_string()),param("x"),WordPart::Literal(" b".to_string())])]);
+        assert_eq!(parts("\"${y}z\""),[WordPart::DoubleQuoted(vec![param("y"),WordPart::Literal("z".to_string())])]);
+        assert_eq!(parts("pre\"'\"post"),[WordPart::Literal("pre".to_string()),WordPart::DoubleQuoted(vec![WordPart::Literal("'".to_string())]),WordPart::Literal("post".to_string())]);
+        assert_eq!(parts("$x$y"),[param("x"),param("y")]);
+        assert_eq!(Lexer::new("\"a").next_token(),Err(SyntaxError::Incomplete));
+    }
+
+    #[test]
+    fn escapes() {
+        assert_eq!(tokens("a\\ b \\|"),[Token::Word(Word {parts: vec![WordPart::Literal("a".to_string()),WordPart::Escaped(' '),WordPart::Literal("b".to_string())]}),Token::Word(Word {parts: vec![WordPart::Escaped('|')]})]);
+        // in double quotes only $ ` " \ and newline can be escaped
+        assert_eq!(parts("\"\\\"\\$\\d\""),[WordPart::DoubleQuoted(vec![WordPart::Literal("\"$\\d".to_string())])]);
+        assert_eq!(parts("'\\n'"),[WordPart::SingleQuoted("\\n".to_string())]);
+    }
+
+    #[test]
+    fn line_continuation() {
+        assert_eq!(tokens("ec\\\nho a\\\n b"),[word("echo"),word("a"),word("b")]);
+        assert_eq!(parts("\"a\\\nb\""),[WordPart::DoubleQuoted(vec![WordPart::Literal("ab".to_string())])]);
+        // a backslash at the very end has nothing to escape
+        assert_eq!(tokens("a\\"),[word("a\\")]);
+    }
+
+    #[test]
+    fn incomplete() {
+        assert_eq!(Lexer::new("'a").next_token(),Err(SyntaxError::Incomplete));
+    }
+}
@@ -1,104 +1,189 @@
+mod arith;
+mod brace;
 mod builtin;
+mod expand;
+mod function;
+mod glob;
 mod job;
-
-use crate::job::{ProccessState,Job,Jobs};
-use std::process::{self,Command, Stdio, Child};
+mod lexer;
+mod options;
+mod parser;
+mod pattern;
+mod terminal;
+
+use crate::function::Functions;
+use crate::job::{JobChange,ProccessState,ProcessStatus,Jobs};
+use crate::lexer::SyntaxError;
+use crate::parser::{AndOr,CaseClause,CaseTerminator,Command as AstCommand,Connector,IfClause,List,ListItem,Pipeline,RedirOp,Redirect,SimpleCommand};
+use std::process::{self,Command};
 use std::env;
+use std::path::Path;
 use std::io::{self,Write};
 use std::fs::File;
 use std::fs::OpenOptions;
 use std::io::prelude::*;
 use std::os::unix::process::CommandExt;
-use signal_hook::{consts::*, iterator::Signals};
+use signal_hook::consts::*;
 use std::thread;
-use nix::unistd::Pid;
-use nix::unistd::pause;
-use nix::sys::signal::{self, Signal};
+use nix::unistd::{self,ForkResult,Pid};
+use nix::fcntl::{fcntl,FcntlArg,OFlag};
+use std::os::unix::io::{AsRawFd,FromRawFd};
+use nix::sys::signal::{self, SigHandler, Signal};
+use nix::errno::Errno;
+use nix::sys::memfd::{memfd_create,MemFdCreateFlag};
 use nix::sys::wait;
 use std::collections::BTreeMap;
+use std::rc::Rc;
+use std::sync::{Condvar,Mutex,MutexGuard};
+use std::sync::atomic::{AtomicBool,AtomicI32,AtomicU32,Ordering};
+
+
+// a redirection after its target has been expanded
+#[derive(Debug)]
+struct PreparedRedirect {
+    fd: i32,
+    op: RedirOp,
+    target: String,
+}
 
+// descriptors and what to point them at, None closing them
+type FdActions = Vec<(i32,Option<i32>)>;
 
-#[derive(Debug,PartialEq)]
-enum Redirection <T>{
-    Normal,
-    Pipe,
-    File(T),
-    Redir(T)
+// a pipeline element after its words have been expanded
+#[derive(Debug)]
+struct PreparedCommand {
+    argv: Vec<String>,
+    env: Vec<(String,String)>,
+    redirects: Vec<PreparedRedirect>,
 }
 
 
 
 const PROMPT: &str = "tsh> ";
-static mut VERBOSE:i32 = 0;
-static mut JOBS: Jobs = Jobs::new();
-static mut EXITSTATUS: Option<i32> = None; 
+const CONTINUATION_PROMPT: &str = "> ";
+static VERBOSE: AtomicBool = AtomicBool::new(false);
+// shared with the signal thread, which only changes it with REAPING held
+static JOBS: Mutex<Jobs> = Mutex::new(Jobs::new());
+// the status of the last command, for $?
+static LAST_STATUS: AtomicI32 = AtomicI32::new(0);
+// set in a forked copy of the shell
+static SUBSHELL: AtomicBool = AtomicBool::new(false);
+// loops being run, and how many of them a break or continue still has to leave
+static LOOP_DEPTH: AtomicU32 = AtomicU32::new(0);
+static BREAKS: AtomicU32 = AtomicU32::new(0);
+static CONTINUES: AtomicU32 = AtomicU32::new(0);
+// set by return until the function call ends
+static RETURNING: AtomicBool = AtomicBool::new(false);
+// the shell's ends of <(...) and >(...) pipes, open until the pipeline
+// that uses them is done
+static PROCESS_FDS: Mutex<Vec<i32>> = Mutex::new(Vec::new());
+// held while children are forked and added to JOBS, so the SIGCHLD
+// thread can't reap one, or let its process group go, before it is known.
+// the signal thread holds it whenever it touches JOBS, so no fork happens
+// while JOBS is locked
+static REAPING: Mutex<()> = Mutex::new(());
+// notified whenever the signal thread has changed a job
+static JOB_CHANGED: Condvar = Condvar::new();
+// the read and write ends of the signal handlers' pipe
+static SIGNAL_FDS: [AtomicI32; 2] = [AtomicI32::new(-1),AtomicI32::new(-1)];
+// set by Ctrl-C, stops whatever is left of the command line
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+// the text of the { ...; } group being run in the foreground, which its
+// commands are listed under as jobs
+static GROUP_TEXT: Mutex<Option<String>> = Mutex::new(None);
+// set when a command in such a group stops, which stops the rest of it
+static GROUP_STOPPED: AtomicBool = AtomicBool::new(false);
+// set in the copy of the shell that goes on with such a group once the
+// stopped command is done, which exits at the end of the group
+static GROUP_REST: AtomicBool = AtomicBool::new(false);
 
 
 
 fn main() {
     let mut aliases: BTreeMap<String,(String,Vec<String>)> = BTreeMap::new();
     let mut variables: BTreeMap<String,String> = BTreeMap::new();
+    let mut functions = Functions::new();
     let args: Vec<String> = env::args().collect();
     let mut emit_prompt = true;
     let mut path_in_prompt = false;
-   
-    if args.len() > 1 {
 
-        if args[1].contains("-") {
-            let mut bad_input = true;
-            if args[1].contains("h") {
-                usage();
-            }
-            if args[1].contains("v") {
-                unsafe { VERBOSE = 1 };
-                bad_input = false;
-            }
-            if args[1].contains("p") {
-                emit_prompt = false;
-                bad_input = false;
-            }
-            if args[1].contains("a") {
-                path_in_prompt = true;
-                bad_input = false;
-            }
-            if bad_input {
-                usage();
-            }
+    if args.len() > 1 && args[1].contains('-') {
+        let mut bad_input = true;
+        if args[1].contains('h') {
+            usage();
+        }
+        if args[1].contains('v') {
+            VERBOSE.store(true,Ordering::Relaxed);
+            bad_input = false;
+        }
+        if args[1].contains('p') {
+            emit_prompt = false;
+            bad_input = false;
         }
-    }    
+        if args[1].contains('a') {
+            path_in_prompt = true;
+            bad_input = false;
+        }
+        if bad_input {
+            usage();
+        }
+    }
 
-    setup_signal_handlers();
+    let interactive = terminal::init();
+    setup_signal_handlers(interactive);
 
-    
-    match parse_rshrc(&mut aliases,&mut variables) {
-        Err(e) => eprintln!("{}",e),
-        Ok(_) => (),
+
+    if let Err(e) = parse_rshrc(&mut aliases,&mut variables,&mut functions) {
+        eprintln!("{}",e);
     }
-    
+
 
 
     loop {
         let mut buffer = String::new();
         if emit_prompt {
             let curr_dir = env::current_dir().unwrap();
-            let print_prompt;
-            if path_in_prompt {
-                print_prompt = format!("tsh {} > ",curr_dir.into_os_string().to_str().unwrap());
+            let print_prompt = if path_in_prompt {
+                format!("tsh {} > ",curr_dir.into_os_string().to_str().unwrap())
             }
             else {
-                print_prompt = PROMPT.to_string();
-            }
+                PROMPT.to_string()
+            };
             print!("{}",print_prompt);
             io::stdout().flush().unwrap();
         }
-        io::stdin().read_line(&mut buffer)
-            .expect("Failed to read line");
 
-        eval(&buffer,&mut aliases,&mut variables);
+        loop {
+            let mut line = String::new();
+            let read = io::stdin().read_line(&mut line)
+                .expect("Failed to read line");
+            if read == 0 {
+                // whatever is left over can only be a syntax error now
+                eval(&buffer,&mut aliases,&mut variables,&mut functions);
+                process::exit(0);
+            }
+            buffer.push_str(&line);
+
+            if !needs_continuation(&buffer) {
+                break;
+            }
+            if emit_prompt {
+                print!("{}",CONTINUATION_PROMPT);
+                io::stdout().flush().unwrap();
+            }
+        }
+
+        reset_jumps();
+        eval(&buffer,&mut aliases,&mut variables,&mut functions);
     }
 }
 
-fn parse_rshrc(aliases: &mut BTreeMap<String, (String,Vec<String>)>, variables: &mut BTreeMap<String, String>) -> std::io::Result<()> {
+// whether the input so far ends in the middle of a command
+fn needs_continuation(input: &str) -> bool {
+    matches!(parser::parse(input), Err(SyntaxError::Incomplete))
+}
+
+fn parse_rshrc(aliases: &mut BTreeMap<String, (String,Vec<String>)>, variables: &mut BTreeMap<String, String>, functions: &mut Functions) -> std::io::Result<()> {
     let key = "HOME";
     match env::var(key) {
         Err(_) => {
@@ -106,16 +191,24 @@ fn parse_rshrc(aliases: &mut BTreeMap<String, (String,Vec<String>)>, variables:
         },
         Ok(val) => {
             let rshrc_location = val + "/.rshrc";
-            
+
             let mut file = File::open(rshrc_location)?;
             let mut contents = String::new();
             file.read_to_string(&mut contents)?;
             let lines: Vec<&str> = contents.split('\n').collect();
 
+            let mut buffer = String::new();
             for line in lines.iter() {
-                eval(line,aliases,variables);
+                buffer.push_str(line);
+                buffer.push('\n');
+                if needs_continuation(&buffer) {
+                    continue;
+                }
+                reset_jumps();
+                eval(&buffer,aliases,variables,functions);
+                buffer.clear();
             }
-
+            eval(&buffer,aliases,variables,functions);
 
 
         }
@@ -123,120 +216,121 @@ fn parse_rshrc(aliases: &mut BTreeMap<String, (String,Vec<String>)>, variables:
     Ok(())
 }
 
-fn setup_signal_handlers() {
+// the handlers write the signal number down a pipe that the signal thread
+// reads. both ends are kept above the descriptors redirections name, where
+// they can't be written to or replaced by mistake
+fn signal_pipe(signals: &[i32]) -> nix::Result<File> {
+    let (read_fd, write_fd) = unistd::pipe2(OFlag::O_CLOEXEC)?;
+    let high_read = fcntl(read_fd,FcntlArg::F_DUPFD_CLOEXEC(10));
+    let high_write = fcntl(write_fd,FcntlArg::F_DUPFD_CLOEXEC(10));
+    let _ = unistd::close(read_fd);
+    let _ = unistd::close(write_fd);
+    let (read_fd, write_fd) = (high_read?,high_write?);
+    // a full pipe already has a wakeup waiting, the handler mustn't block
+    fcntl(write_fd,FcntlArg::F_SETFL(OFlag::O_NONBLOCK))?;
+    SIGNAL_FDS[0].store(read_fd,Ordering::Relaxed);
+    SIGNAL_FDS[1].store(write_fd,Ordering::Relaxed);
+
+    for sig in signals.iter().copied() {
+        let result = unsafe {
+            signal_hook::low_level::register(sig,move || {
+                let _ = unistd::write(write_fd,&[sig as u8]);
+            })
+        };
+        result.map_err(|e| Errno::from_i32(e.raw_os_error().unwrap_or(0)))?;
+    }
+    Ok(unsafe { File::from_raw_fd(read_fd) })
+}
 
-   let signals = Signals::new(&[SIGINT,SIGCHLD,SIGTSTP]);
+// descriptors the shell uses for itself, which redirections treat as closed
+fn is_internal(fd: i32) -> bool {
+    SIGNAL_FDS.iter().any(|internal| internal.load(Ordering::Relaxed) == fd) || terminal::is_terminal_fd(fd)
+}
+
+// with a terminal, Ctrl-Z goes straight to the foreground job and the
+// shell ignores SIGTSTP itself
+fn setup_signal_handlers(interactive: bool) {
+
+    let signals: &[i32] = if interactive { &[SIGINT,SIGCHLD] } else { &[SIGINT,SIGCHLD,SIGTSTP] };
+    let mut pipe = match signal_pipe(signals) {
+        Ok(pipe) => pipe,
+        Err(e) => {
+            eprintln!("tsh: cannot set up signal handlers: {}",e.desc());
+            process::exit(1);
+        }
+    };
     thread::spawn(move || {
-        for sig in &mut signals.unwrap(){
+        let mut buf = [0u8; 1];
+        while pipe.read_exact(&mut buf).is_ok() {
+            let sig = buf[0] as i32;
 
             if sig == SIGINT {
-                if unsafe {VERBOSE} == 1 {
+                if verbose() {
                     println!("sigint_handler");
                 }
-                
-                for job in unsafe {JOBS.iter_mut()} {
-                    
-                    match job.state {
-                        ProccessState::FG => {
-                                
-                                signal::kill(Pid::from_raw(-job.pgid),Signal::SIGINT).unwrap();
-                                unsafe {
-                                    match JOBS.delete_job(*job.pids.last().unwrap()) {
-                                        Err(e) => eprintln!("{}",e),
-                                        Ok(_) => (),
-                                    }
-                                }
-
-                        }
-                        _ => {
-                                continue;
-                        }
+                INTERRUPTED.store(true,Ordering::Relaxed);
+
+                // the SIGCHLD that follows finishes the jobs off
+                let _reaping = REAPING.lock().unwrap();
+                let mut jobs = JOBS.lock().unwrap();
+                for job in jobs.iter_mut() {
+                    if let ProccessState::FG = job.state {
+                        let _ = signal::kill(Pid::from_raw(-job.pgid),Signal::SIGINT);
                     }
-
                 }
+                drop(jobs);
+                JOB_CHANGED.notify_all();
 
             }
             else if sig == SIGCHLD {
-                if unsafe {VERBOSE} == 1 {
+                if verbose() {
                     println!("sigchild_handler");
                 }
                 let flags: wait::WaitPidFlag = wait::WaitPidFlag::WNOHANG | wait::WaitPidFlag::WUNTRACED;
+                let _reaping = REAPING.lock().unwrap();
+                let mut jobs = JOBS.lock().unwrap();
 
                 loop {
-                    match wait::waitpid(Pid::from_raw(-1), Some(flags)) {
-                        Err(_) => break,
-                        Ok(x) => {
-                            match x {
-                                wait::WaitStatus::StillAlive => break,
-                                wait::WaitStatus::Exited(pid,status) => {
-                                    unsafe { 
-                                        EXITSTATUS = Some(status);
-                                        match JOBS.delete_job(pid.as_raw()) {
-                                            Ok(_) => (),
-                                            Err(_) => (),
-                                        } 
-                                    };
-                                    //this makes sure that waitfg can exit no matter what
-                                    signal::kill(Pid::from_raw(process::id().try_into().unwrap()),Signal::SIGCHLD).unwrap();
-
-                                }
-                                wait::WaitStatus::Signaled(pid, signal, _core_dump) => {
-                                    let job;
-                                    unsafe { 
-                                        match JOBS.get_job_pid(pid.as_raw()) {
-                                            Some(x) => {
-                                                job = x;
-                                                println!("Job [{}] ({}) terminated by signal {}",job.jid,pid,signal);
-                                            },
-                                            None => (),
-                                        } 
-                                    };
-
-                                    unsafe { 
-                                        EXITSTATUS = None;
-                                        match JOBS.delete_job(pid.as_raw()) {
-                                            Ok(_) => (),
-                                            Err(_) => (),
-                                        } 
-                                    };
-                                },
-                                wait::WaitStatus::Stopped(pid,signal) => {
-                                    let mut job;
-                                    unsafe { job = JOBS.get_job_pid(pid.as_raw()).unwrap(); };
-                                    
-                                    job.state = ProccessState::ST;
-                                     
-                                    
-                                    println!("Job [{}] ({}) stopped by signal {}",job.jid,pid,signal);
-                                }
-                                _ => (),
-                            }
-                        }
+                    let (pid, status) = match wait::waitpid(Pid::from_raw(-1), Some(flags)) {
+                        Ok(wait::WaitStatus::Exited(pid, code)) => (pid.as_raw(),ProcessStatus::Exited(code)),
+                        Ok(wait::WaitStatus::Signaled(pid, signal, _core_dump)) => (pid.as_raw(),ProcessStatus::Signaled(signal)),
+                        Ok(wait::WaitStatus::Stopped(pid, signal)) => (pid.as_raw(),ProcessStatus::Stopped(signal)),
+                        Ok(wait::WaitStatus::StillAlive) | Err(_) => break,
+                        Ok(_) => continue,
+                    };
 
+                    // Ctrl-C reaches the foreground job rather than the
+                    // shell, which still has to stop the command line
+                    if status == ProcessStatus::Signaled(Signal::SIGINT) && jobs.get_job_pid(pid).is_some_and(|job| matches!(job.state, ProccessState::FG)) {
+                        INTERRUPTED.store(true,Ordering::Relaxed);
                     }
-                }
-                        
-                    
-                
 
+                    match jobs.update(pid,status) {
+                        JobChange::Stopped {jid, pgid, signal} => {
+                            println!("Job [{}] ({}) stopped by signal {}",jid,pgid,signal);
+                        },
+                        // a pipe closing early isn't worth a word
+                        JobChange::Done {jid, pgid, status: ProcessStatus::Signaled(signal)} if signal != Signal::SIGPIPE => {
+                            println!("Job [{}] ({}) terminated by signal {}",jid,pgid,signal);
+                        },
+                        _ => (),
+                    }
+                }
+                drop(jobs);
+                JOB_CHANGED.notify_all();
 
             }
             else if sig == SIGTSTP {
 
-                if unsafe {VERBOSE} == 1 {
+                if verbose() {
                     println!("sigtstp_handler");
                 }
-                
-                for job in unsafe {JOBS.iter_mut()} {
-                    
-                    match job.state {
-                        ProccessState::FG => {
 
-                                signal::kill(Pid::from_raw(-job.pgid),Signal::SIGTSTP).unwrap();        
-                        }
-                        _ => {
-                                continue;
-                        }
+                let _reaping = REAPING.lock().unwrap();
+                for job in JOBS.lock().unwrap().iter_mut() {
+
+                    if let ProccessState::FG = job.state {
+                        let _ = signal::kill(Pid::from_raw(-job.pgid),Signal::SIGTSTP);
                     }
 
                 }
@@ -248,784 +342,1406 @@ fn setup_signal_handlers() {
 }
 
 
-fn eval(cmdline: &str, aliases: &mut BTreeMap<String,(String,Vec<String>)>, variables: &mut BTreeMap<String, String>) {
-    if unsafe { VERBOSE == 1 } {
+fn eval(cmdline: &str, aliases: &mut BTreeMap<String,(String,Vec<String>)>, variables: &mut BTreeMap<String, String>, functions: &mut Functions) {
+    if verbose() {
         println!("Eval");
     }
-    let argv: Vec<String>;
-    let bg: bool;
-    let pair = parseline(&cmdline);
-    bg = pair.0;
-    argv = pair.1;
-    let append = pair.2;
-    
-    if unsafe { VERBOSE == 1 } {
-        println!("{:?}",argv);
-    }
 
-    if builtin_cmd(&argv,aliases,variables) == 1 {
-        return;
+    let list = match parser::parse(cmdline) {
+        Ok(list) => list,
+        Err(e) => {
+            eprintln!("tsh: {}",e);
+            return;
+        }
+    };
+
+    if verbose() {
+        println!("{:?}",list);
     }
 
-    let set = parseargs(&argv,&aliases,variables);
+    run_list(&list,aliases,variables,functions);
+}
 
-    let cmds = set.0;
-    let args = set.1;
-    let env = set.2;
-    let stdin_redir = set.3;
-    let stdout_redir = set.4;
+pub fn last_status() -> i32 {
+    LAST_STATUS.load(Ordering::Relaxed)
+}
 
-    create_subproccesses(cmdline,argv,cmds, args, env,stdin_redir, stdout_redir,bg,append);
+// a command that gave no status, like one that was stopped, counts as 0
+fn set_status(status: Option<i32>) {
+    LAST_STATUS.store(status.unwrap_or(0),Ordering::Relaxed);
 }
 
-fn waitpid(pid: Pid, flags: Option<wait::WaitPidFlag>) {
-
-    match wait::waitpid(pid, flags) {
-        Err(_) => return,
-        Ok(x) => {
-            match x {
-                wait::WaitStatus::StillAlive => return,
-                wait::WaitStatus::Exited(pid,status) => {
-                    unsafe { 
-                        EXITSTATUS = Some(status);
-                        match JOBS.delete_job(pid.as_raw()) {
-                            Ok(_) => (),
-                            Err(_) => (),
-                        } 
-                    };
+fn verbose() -> bool {
+    VERBOSE.load(Ordering::Relaxed)
+}
 
-                }
-                wait::WaitStatus::Signaled(pid, signal, _core_dump) => {
-                    let job;
-                    unsafe { 
-                        match JOBS.get_job_pid(pid.as_raw()) {
-                            Some(x) => {
-                                job = x;
-                                println!("Job [{}] ({}) terminated by signal {}",job.jid,pid,signal);
-                            },
-                            None => (),
-                        } 
-                    };
+// whether a break, continue or Ctrl-C means the rest of a list is skipped
+fn jumping() -> bool {
+    INTERRUPTED.load(Ordering::Relaxed) || GROUP_STOPPED.load(Ordering::Relaxed) || RETURNING.load(Ordering::Relaxed) || BREAKS.load(Ordering::Relaxed) > 0 || CONTINUES.load(Ordering::Relaxed) > 0
+}
 
-                    unsafe { 
-                        EXITSTATUS = None;
-                        match JOBS.delete_job(pid.as_raw()) {
-                            Ok(_) => (),
-                            Err(_) => (),
-                        } 
-                    };
+// forgets about a Ctrl-C or break left over from the last command line
+fn reset_jumps() {
+    INTERRUPTED.store(false,Ordering::Relaxed);
+    GROUP_STOPPED.store(false,Ordering::Relaxed);
+    RETURNING.store(false,Ordering::Relaxed);
+    BREAKS.store(0,Ordering::Relaxed);
+    CONTINUES.store(0,Ordering::Relaxed);
+}
+
+// runs the elements of a list one after another and returns the status of the last
+fn run_list(list: &List, aliases: &mut BTreeMap<String,(String,Vec<String>)>, variables: &mut BTreeMap<String, String>, functions: &mut Functions) -> Option<i32> {
+    let mut status = Some(0);
+    for item in list.items.iter() {
+        if jumping() {
+            break;
+        }
+        if item.bg && (!item.and_or.rest.is_empty() || is_compound(&item.and_or.first)) {
+            run_in_background(item,aliases,variables,functions);
+            status = Some(0);
+        }
+        else {
+            status = run_and_or(&item.text,&item.and_or,item.bg,aliases,variables,functions);
+            if item.bg {
+                status = Some(0);
+            }
+        }
+    }
+    status
+}
+
+fn is_compound(pipeline: &Pipeline) -> bool {
+    pipeline.commands.iter().any(|command| !matches!(command, AstCommand::Simple(_)))
+}
+
+fn run_compound(cmdline: &str, command: &AstCommand, aliases: &mut BTreeMap<String,(String,Vec<String>)>, variables: &mut BTreeMap<String, String>, functions: &mut Functions) -> Option<i32> {
+    let status = match command {
+        AstCommand::Simple(_) => unreachable!(),
+        AstCommand::If(clause) => run_if(clause,aliases,variables,functions),
+        AstCommand::Group(list) => run_group(cmdline,list,aliases,variables,functions),
+        AstCommand::Subshell(list) => run_subshell(cmdline,list,aliases,variables,functions),
+        AstCommand::Redirected(command, redirects) => {
+            let saved = prepare_redirects(redirects,aliases,variables,functions).and_then(|redirects| redirect_shell(&redirects));
+            match saved {
+                Ok(saved) => {
+                    let status = run_compound(cmdline,command,aliases,variables,functions);
+                    restore_shell(saved);
+                    status
                 },
-                wait::WaitStatus::Stopped(pid,signal) => {
-                    let mut job;
-                    unsafe { job = JOBS.get_job_pid(pid.as_raw()).unwrap(); };
-                    
-                    job.state = ProccessState::ST;
-                     
-                    
-                    println!("Job [{}] ({}) stopped by signal {}",job.jid,pid,signal);
+                Err(e) => {
+                    eprintln!("tsh: {}",e);
+                    Some(1)
                 }
-                _ => (),
             }
-        }
+        },
+        AstCommand::Function(def) => {
+            functions.define(&def.name,(*def.body).clone());
+            Some(0)
+        },
+        AstCommand::Case(clause) => run_case(clause,aliases,variables,functions),
+        AstCommand::Loop(_) | AstCommand::For(_) | AstCommand::ArithFor(_) => {
+            LOOP_DEPTH.fetch_add(1,Ordering::Relaxed);
+            let status = run_loop(command,aliases,variables,functions);
+            LOOP_DEPTH.fetch_sub(1,Ordering::Relaxed);
+            status
+        },
+    };
+    set_status(status);
+    status
+}
 
+// a group in the foreground is one job, its commands are listed under the
+// text of the outermost group and stopping one of them stops the rest
+fn run_group(cmdline: &str, list: &List, aliases: &mut BTreeMap<String,(String,Vec<String>)>, variables: &mut BTreeMap<String, String>, functions: &mut Functions) -> Option<i32> {
+    let outermost = GROUP_TEXT.lock().unwrap().is_none();
+    if outermost {
+        *GROUP_TEXT.lock().unwrap() = Some(cmdline.to_string());
+    }
+    let status = run_list(list,aliases,variables,functions);
+    if outermost {
+        *GROUP_TEXT.lock().unwrap() = None;
+        if GROUP_REST.load(Ordering::Relaxed) {
+            exit_shell(status);
+        }
     }
+    status
+}
 
+// the text a foreground job is listed under
+fn job_text(cmdline: &str) -> String {
+    GROUP_TEXT.lock().unwrap().clone().unwrap_or_else(|| cmdline.to_string())
+}
 
+fn run_if(clause: &IfClause, aliases: &mut BTreeMap<String,(String,Vec<String>)>, variables: &mut BTreeMap<String, String>, functions: &mut Functions) -> Option<i32> {
+    for (condition, body) in clause.branches.iter() {
+        if run_list(condition,aliases,variables,functions) == Some(0) {
+            return run_list(body,aliases,variables,functions);
+        }
+    }
+    match &clause.else_branch {
+        Some(body) => run_list(body,aliases,variables,functions),
+        None => Some(0),
+    }
 }
 
-fn wait_conditional(pid: i32) {
-    
-    loop {
-        let job = unsafe {JOBS.get_job_pid(pid)};
-       
-        match job {
-            Some(x) => match x.state {
-                ProccessState::FG => {
-                    if unsafe { VERBOSE == 1} {
-                        println!("{}", x);
-                        println!("Pausing conditional")
-                    }
-                    //let flags: wait::WaitPidFlag = wait::WaitPidFlag::WNOHANG | wait::WaitPidFlag::WUNTRACED;
-                    //pause(); 
-                    //waitpid(Pid::from_raw(-pid),Some(flags))
-                }, 
-                ProccessState::BG => {
-                    if unsafe { VERBOSE == 1} {
-                        println!("{}", x);
-                        println!("Pausing conditional")
+fn run_case(clause: &CaseClause, aliases: &mut BTreeMap<String,(String,Vec<String>)>, variables: &mut BTreeMap<String, String>, functions: &mut Functions) -> Option<i32> {
+    let word = match expand::expand_string(&clause.word,aliases,variables,functions) {
+        Ok(word) => word,
+        Err(e) => {
+            eprintln!("tsh: {}",e);
+            return Some(1);
+        }
+    };
+
+    let mut status = Some(0);
+    let mut fall_through = false;
+    for item in clause.items.iter() {
+        if !fall_through {
+            let mut matched = false;
+            for pattern in item.patterns.iter() {
+                match expand::expand_pattern(pattern,aliases,variables,functions) {
+                    Ok(pattern) if pattern.matches(&word) => {
+                        matched = true;
+                        break;
+                    },
+                    Ok(_) => (),
+                    Err(e) => {
+                        eprintln!("tsh: {}",e);
+                        return Some(1);
                     }
-                    //let flags: wait::WaitPidFlag = wait::WaitPidFlag::WNOHANG | wait::WaitPidFlag::WUNTRACED;
-                    //pause(); 
-                    //waitpid(Pid::from_raw(-pid),Some(flags))
-                },
-                _ => break
+                }
+            }
+            if !matched {
+                continue;
             }
-            None => break,
+        }
+
+        status = run_list(&item.body,aliases,variables,functions);
+        match item.terminator {
+            CaseTerminator::Break => break,
+            CaseTerminator::FallThrough => fall_through = true,
+            CaseTerminator::Continue => fall_through = false,
         }
     }
+    status
+}
 
-    if unsafe { VERBOSE == 1} {
-        println!("Broke out");
+// runs a function body with its own positional parameters and locals
+fn run_function(body: Rc<AstCommand>, argv: &[String], aliases: &mut BTreeMap<String,(String,Vec<String>)>, variables: &mut BTreeMap<String, String>, functions: &mut Functions) -> Option<i32> {
+    functions.push_frame(&argv[1..]);
+    // break and continue don't reach the loops of the caller
+    let loops = LOOP_DEPTH.swap(0,Ordering::Relaxed);
+
+    let status = run_compound(&argv.join(" "),&body,aliases,variables,functions);
+
+    LOOP_DEPTH.store(loops,Ordering::Relaxed);
+    RETURNING.store(false,Ordering::Relaxed);
+    for (name, value) in functions.pop_frame() {
+        match value {
+            Some(value) => builtin::variable(&name,&value,variables),
+            None => {
+                variables.remove(&name);
+            },
+        }
     }
+    set_status(status);
+    status
 }
 
-fn create_subproccesses(cmdline:&str,argv: Vec<String>,cmds: Vec<String>, args: Vec<Vec<String>>, env: Vec<(String,String)>,stdin_redir: Vec<Redirection<usize>>, stdout_redir: Vec<Redirection<usize>>,bg: bool, append: bool) -> Option<i32> {
+// return [n]
+fn return_builtin(argv: &[String], functions: &Functions) -> i32 {
+    if !functions.in_function() {
+        eprintln!("return: can only `return' from a function");
+        return 1;
+    }
+    let status = match argv.get(1).map(|arg| arg.parse::<i32>()) {
+        None => last_status(),
+        Some(Ok(status)) => status & 0xff,
+        Some(Err(_)) => {
+            eprintln!("return: {}: numeric argument required",argv[1]);
+            2
+        },
+    };
+    RETURNING.store(true,Ordering::Relaxed);
+    status
+}
 
-    if unsafe { VERBOSE == 1 } {
-        println!("cmds {:?}",cmds);
-        println!("args {:?}",args);
-        println!("env {:?}",env);
-        println!("stdin {:?}",stdin_redir);
-        println!("stdout {:?}",stdout_redir);
-        println!("bg {:?}",bg);
-        
-        println!("\npid = {}", process::id());
+// deals with a break or continue once a loop body is done, returns whether
+// the loop has to stop
+fn end_of_body() -> bool {
+    if INTERRUPTED.load(Ordering::Relaxed) || GROUP_STOPPED.load(Ordering::Relaxed) || RETURNING.load(Ordering::Relaxed) {
+        return true;
+    }
+    let breaks = BREAKS.load(Ordering::Relaxed);
+    if breaks > 0 {
+        BREAKS.store(breaks - 1,Ordering::Relaxed);
+        return true;
     }
-    
-    let mut processes: Vec<Option<Child>> = Vec::new(); 
-    let mut pids: Vec<i32> = Vec::new(); 
-    let mut group_id = 0;
-    for i in 0..cmds.len() {
-        
-        if cmds[i] == "&&" {
-            if unsafe { VERBOSE == 1} {
-                println!("trying conditional exec");
+    // continue N leaves N - 1 loops and goes on with the next
+    let continues = CONTINUES.load(Ordering::Relaxed);
+    if continues > 0 {
+        CONTINUES.store(continues - 1,Ordering::Relaxed);
+        return continues > 1;
+    }
+    false
+}
+
+fn run_loop(command: &AstCommand, aliases: &mut BTreeMap<String,(String,Vec<String>)>, variables: &mut BTreeMap<String, String>, functions: &mut Functions) -> Option<i32> {
+    let mut status = Some(0);
+    match command {
+        AstCommand::Loop(clause) => loop {
+            let condition = run_list(&clause.condition,aliases,variables,functions);
+            if jumping() {
+                end_of_body();
+                break;
             }
-            let state;
-            if !bg {
-                state = ProccessState::FG;
+            if (condition == Some(0)) == clause.until {
+                break;
             }
-            else {
-                state = ProccessState::BG
+            status = run_list(&clause.body,aliases,variables,functions);
+            if end_of_body() {
+                break;
             }
-            
-            unsafe {
-                JOBS.addjob(&pids, pids[0], state, cmdline);
-                wait_conditional(pids[0]);
-
-                if EXITSTATUS == Some(0) {
-                    pids.push(0);
-                    processes.push(None);
-                    group_id = 0;
-                    continue;
+        },
+        AstCommand::For(clause) => {
+            let words = match &clause.words {
+                Some(words) => match expand::expand_words(words,aliases,variables,functions) {
+                    Ok(words) => words,
+                    Err(e) => {
+                        eprintln!("tsh: {}",e);
+                        return Some(1);
+                    }
+                },
+                // for name; loops over the positional parameters
+                None => functions.args().to_vec(),
+            };
+            for word in words {
+                builtin::variable(&clause.name,&word,variables);
+                status = run_list(&clause.body,aliases,variables,functions);
+                if end_of_body() {
+                    break;
                 }
-                else {
-                    break
-                } 
             }
-        }
-        else if cmds[i] == "||" {
-            let state;
-            if !bg {
-                state = ProccessState::FG;
-            }
-            else {
-                state = ProccessState::BG
+        },
+        AstCommand::ArithFor(clause) => {
+            if let Err(e) = expand::expand_arith(&clause.init,aliases,variables,functions) {
+                eprintln!("tsh: {}",e);
+                return Some(1);
             }
-            
-            unsafe {
-                JOBS.addjob(&pids, pids[0], state, cmdline);
-                wait_conditional(pids[0]);
-
-                if EXITSTATUS != Some(0) {
-                    pids.push(0);
-                    processes.push(None);
-                    group_id = 0;
-                    continue;
+            loop {
+                // an empty condition is always true
+                let condition = if clause.condition.parts.is_empty() { Ok(1) } else { expand::expand_arith(&clause.condition,aliases,variables,functions) };
+                match condition {
+                    Ok(0) => break,
+                    Ok(_) => (),
+                    Err(e) => {
+                        eprintln!("tsh: {}",e);
+                        return Some(1);
+                    }
                 }
-                else {
-                    break
+                status = run_list(&clause.body,aliases,variables,functions);
+                if end_of_body() {
+                    break;
+                }
+                if let Err(e) = expand::expand_arith(&clause.step,aliases,variables,functions) {
+                    eprintln!("tsh: {}",e);
+                    return Some(1);
                 }
+            }
+        },
+        _ => unreachable!(),
+    }
+    status
+}
+
+// break [n] and continue [n]
+fn loop_control(argv: &[String]) -> i32 {
+    let depth = LOOP_DEPTH.load(Ordering::Relaxed);
+    let levels = match argv.get(1).map(|arg| arg.parse::<u32>()) {
+        None => 1,
+        Some(Ok(levels)) if levels > 0 => levels,
+        Some(Ok(_)) => {
+            eprintln!("{}: {}: loop count out of range",argv[0],argv[1]);
+            return 1;
+        },
+        Some(Err(_)) => {
+            eprintln!("{}: {}: numeric argument required",argv[0],argv[1]);
+            return 1;
+        },
+    };
+    if depth == 0 {
+        eprintln!("{}: only meaningful in a `for', `while', or `until' loop",argv[0]);
+        return 0;
+    }
+
+    let jumps = if argv[0] == "break" { &BREAKS } else { &CONTINUES };
+    jumps.store(levels.min(depth),Ordering::Relaxed);
+    0
+}
+
+// runs a list in a forked copy of the shell, as one job
+fn run_subshell(cmdline: &str, list: &List, aliases: &mut BTreeMap<String,(String,Vec<String>)>, variables: &mut BTreeMap<String, String>, functions: &mut Functions) -> Option<i32> {
+    let subshell = SUBSHELL.load(Ordering::Relaxed);
+    let group_id = if subshell { unistd::getpgrp().as_raw() } else { 0 };
+    let reaping = hold_reaping();
+    match fork_shell(group_id) {
+        Ok(None) => exit_shell(run_list(list,aliases,variables,functions)),
+        Ok(Some(child)) => {
+            let status = if subshell {
+                wait_child(child)
+            }
+            else {
+                JOBS.lock().unwrap().addjob(&[child.as_raw()], child.as_raw(), ProccessState::FG, &job_text(cmdline));
+                drop(reaping);
+                waitfg(child.as_raw())
+            };
+            set_status(status);
+            status
+        },
+        Err(e) => {
+            eprintln!("tsh: fork: {}",e);
+            Some(1)
+        }
+    }
+}
+
+// a forked shell has no SIGCHLD thread to keep out, and its copy of the
+// lock may have been held when it was forked
+fn hold_reaping() -> Option<MutexGuard<'static,()>> {
+    if SUBSHELL.load(Ordering::Relaxed) {
+        return None;
+    }
+    Some(REAPING.lock().unwrap())
+}
 
+// waits for a child of a forked shell, which has no signal thread reaping them
+fn wait_child(pid: Pid) -> Option<i32> {
+    match wait::waitpid(pid,None) {
+        Ok(wait::WaitStatus::Exited(_, code)) => Some(code),
+        Ok(wait::WaitStatus::Signaled(_, signal, _)) => Some(128 + signal as i32),
+        _ => None,
+    }
+}
+
+// forks a copy of the shell into the given process group, 0 for a new one.
+// returns the pid of the copy, or None inside the copy itself
+fn fork_shell(group_id: i32) -> nix::Result<Option<Pid>> {
+    io::stdout().flush().unwrap();
+    match unsafe { unistd::fork() }? {
+        ForkResult::Child => {
+            let _ = unistd::setpgid(Pid::from_raw(0),Pid::from_raw(group_id));
+            for sig in [Signal::SIGINT,Signal::SIGCHLD] {
+                let _ = unsafe { signal::signal(sig,SigHandler::SigDfl) };
             }
-            
+            terminal::forget();
+            SUBSHELL.store(true,Ordering::Relaxed);
+            Ok(None)
+        },
+        ForkResult::Parent {child} => {
+            let _ = unistd::setpgid(child,Pid::from_raw(group_id));
+            Ok(Some(child))
+        },
+    }
+}
+
+fn exit_shell(status: Option<i32>) -> ! {
+    io::stdout().flush().unwrap();
+    process::exit(status.unwrap_or(1));
+}
+
+// an and-or list or compound command in the background runs in a forked
+// copy of the shell, so that it can wait for its own pipelines
+fn run_in_background(item: &ListItem, aliases: &mut BTreeMap<String,(String,Vec<String>)>, variables: &mut BTreeMap<String, String>, functions: &mut Functions) {
+    let _reaping = hold_reaping();
+    match fork_shell(0) {
+        Ok(None) => exit_shell(run_and_or(&item.text,&item.and_or,false,aliases,variables,functions)),
+        Ok(Some(child)) => {
+            JOBS.lock().unwrap().addjob(&[child.as_raw()], child.as_raw(), ProccessState::BG, &item.text);
+        },
+        Err(e) => eprintln!("tsh: fork: {}",e),
+    }
+}
+
+// runs the pipelines left to right, each one only if the status of the one
+// before lets it
+fn run_and_or(cmdline: &str, and_or: &AndOr, bg: bool, aliases: &mut BTreeMap<String,(String,Vec<String>)>, variables: &mut BTreeMap<String, String>, functions: &mut Functions) -> Option<i32> {
+    let mut status = run_pipeline(cmdline,&and_or.first,bg,aliases,variables,functions);
+
+    for (connector, pipeline) in and_or.rest.iter() {
+        if verbose() {
+            println!("trying conditional exec");
         }
 
-        let mut command: &mut Command = &mut Command::new(cmds[i].as_str());
-        command = command.process_group(group_id);
-        //println!("{}",cmds[i].len());
-        command = command.args(args[i].as_slice());
-        
-        for (key, val) in env.iter() {
-            command = command.env(key,val);
+        let run = match connector {
+            Connector::And => status == Some(0),
+            Connector::Or => status != Some(0),
+        };
+        if run && !jumping() {
+            status = run_pipeline(cmdline,pipeline,false,aliases,variables,functions);
         }
+    }
 
+    status
+}
 
-        match stdout_redir[i] {
-            Redirection::Pipe => {
-                command = command.stdout(Stdio::piped());
-            },
-            Redirection::File(pos) => {
-                let file;
-                if append {
+fn run_pipeline(cmdline: &str, pipeline: &Pipeline, bg: bool, aliases: &mut BTreeMap<String,(String,Vec<String>)>, variables: &mut BTreeMap<String, String>, functions: &mut Functions) -> Option<i32> {
+    let process_fds = PROCESS_FDS.lock().unwrap().len();
+    let status = create_subproccesses(cmdline,pipeline,bg,aliases,variables,functions);
+    close_process_fds(process_fds);
+    if !pipeline.negated || bg {
+        return status;
+    }
 
-                    file = OpenOptions::new()
-                        .write(true)
-                        .append(append)
-                        .open(argv[pos].as_str())
-                        .expect("Bad file path");
+    let status = if status == Some(0) { 1 } else { 0 };
+    set_status(Some(status));
+    Some(status)
+}
+
+fn prepare_command(simple: &SimpleCommand, aliases: &mut BTreeMap<String,(String,Vec<String>)>, variables: &mut BTreeMap<String, String>, functions: &mut Functions) -> Result<PreparedCommand,String> {
+
+    let mut argv = Vec::new();
+    let mut words = simple.words.as_slice();
+    if let Some(alias) = words.first().and_then(|word| word.literal()).and_then(|name| aliases.get(name)) {
+        argv.push(alias.0.clone());
+        argv.extend(alias.1.iter().cloned());
+        words = &words[1..];
+    }
+    argv.extend(expand::expand_words(words,aliases,variables,functions)?);
+
+    let mut env = Vec::new();
+    for assignment in simple.assignments.iter() {
+        env.push((assignment.name.clone(),expand::expand_string(&assignment.value,aliases,variables,functions)?));
+    }
+
+    let redirects = prepare_redirects(&simple.redirects,aliases,variables,functions)?;
+
+    Ok(PreparedCommand {argv, env, redirects})
+}
+
+fn prepare_redirects(redirects: &[Redirect], aliases: &mut BTreeMap<String,(String,Vec<String>)>, variables: &mut BTreeMap<String, String>, functions: &mut Functions) -> Result<Vec<PreparedRedirect>,String> {
+    let mut prepared = Vec::new();
+    for redirect in redirects.iter() {
+        let target = expand::expand_string(&redirect.target,aliases,variables,functions)?;
+        let mut op = redirect.op;
+        // >&word without a descriptor on either side is &>word
+        if op == RedirOp::DupOutput && redirect.fd.is_none() && target != "-" && !is_fd(&target) {
+            op = RedirOp::OutputAll;
+        }
+        prepared.push(PreparedRedirect {fd: redirect.fd.unwrap_or(op.default_fd()), op, target});
+    }
+    Ok(prepared)
+}
+
+fn is_fd(text: &str) -> bool {
+    !text.is_empty() && text.chars().all(|c| c.is_ascii_digit())
+}
+
+// whether a descriptor is open once the redirections so far are applied
+fn is_open(fd: i32, actions: &[(i32,Option<i32>)]) -> bool {
+    match actions.iter().rev().find(|(target, _)| *target == fd) {
+        Some((_, source)) => source.is_some(),
+        None => !is_internal(fd) && fcntl(fd,FcntlArg::F_GETFD).is_ok(),
+    }
+}
 
+// opens the redirection targets, left to right, and works out which
+// descriptor each one points at what, None closing it. the files are kept
+// above the descriptors a command normally names so applying one
+// redirection can't replace the file another one is about to use
+fn open_redirects(redirects: &[PreparedRedirect]) -> Result<(FdActions,Vec<File>),String> {
+    let mut actions = Vec::new();
+    let mut files = Vec::new();
+    for redirect in redirects.iter() {
+        let path = &redirect.target;
+        let file = match redirect.op {
+            RedirOp::DupInput | RedirOp::DupOutput => {
+                if path == "-" {
+                    actions.push((redirect.fd,None));
+                    continue;
                 }
-                else {
-                    file = File::create(argv[pos].as_str()).expect("Bad file path");
+                if !is_fd(path) {
+                    return Err(format!("{}: ambiguous redirect",path));
                 }
-                command = command.stdout(file);
+                let source = match path.parse() {
+                    Ok(source) if is_open(source,&actions) => source,
+                    _ => return Err(format!("{}: Bad file descriptor",path)),
+                };
+                actions.push((redirect.fd,Some(source)));
+                continue;
             },
-            _ => (),
-        }
-        match stdin_redir[i] {
-            Redirection::Pipe => {
-                command = command.stdin(processes[i-1].as_mut().unwrap().stdout.take().unwrap()); 
+            RedirOp::HereDoc => here_document(path).map_err(|e| format!("here-document: {}",io_error(&e)))?,
+            RedirOp::HereString => here_document(&format!("{}\n",path)).map_err(|e| format!("here-string: {}",io_error(&e)))?,
+            op => {
+                let file = match op {
+                    RedirOp::Input => File::open(path),
+                    // noclobber only lets > create files, or write to ones
+                    // like /dev/null that aren't regular files
+                    RedirOp::Output | RedirOp::OutputAll if options::enabled(&options::NOCLOBBER) => match std::fs::metadata(path) {
+                        Ok(metadata) if metadata.is_file() => return Err(format!("{}: cannot overwrite existing file",path)),
+                        Ok(_) => OpenOptions::new().write(true).open(path),
+                        Err(_) => OpenOptions::new().write(true).create_new(true).open(path),
+                    },
+                    RedirOp::Output | RedirOp::Clobber | RedirOp::OutputAll => File::create(path),
+                    RedirOp::Append | RedirOp::AppendAll => OpenOptions::new().create(true).append(true).open(path),
+                    _ => OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path),
+                };
+                file.map_err(|e| format!("{}: {}",path,io_error(&e)))?
             },
-            Redirection::File(pos) => {
-                println!("{}",pos);
-                match File::open(argv[pos].as_str()) {
-                    Ok(file) => command = command.stdin(file),
-                    Err(_) => {
-                        eprintln!("Bad file path");
-                        unsafe { EXITSTATUS = Some(1) };
-                        continue;
-                    }
+        };
+        let fd = fcntl(file.as_raw_fd(),FcntlArg::F_DUPFD_CLOEXEC(10)).map_err(|e| format!("{}: {}",path,e.desc()))?;
+        let file = unsafe { File::from_raw_fd(fd) };
+
+        if matches!(redirect.op, RedirOp::OutputAll | RedirOp::AppendAll) {
+            actions.push((1,Some(fd)));
+            actions.push((2,Some(fd)));
+        }
+        else {
+            actions.push((redirect.fd,Some(fd)));
+        }
+        files.push(file);
+    }
+    Ok((actions,files))
+}
+
+// the system's message for an error, without the (os error n) after it
+fn io_error(e: &io::Error) -> String {
+    match e.raw_os_error() {
+        Some(errno) => Errno::from_i32(errno).desc().to_string(),
+        None => e.to_string(),
+    }
+}
+
+// the body of a here-document in an in-memory file, read from its start
+fn here_document(body: &str) -> io::Result<File> {
+    let fd = memfd_create(c"tsh-heredoc",MemFdCreateFlag::MFD_CLOEXEC)?;
+    let mut file = unsafe { File::from_raw_fd(fd) };
+    file.write_all(body.as_bytes())?;
+    file.seek(io::SeekFrom::Start(0))?;
+    Ok(file)
+}
+
+// points the shell's own descriptors at the redirection targets and returns
+// copies of what they pointed at before, None where they were closed
+fn redirect_shell(redirects: &[PreparedRedirect]) -> Result<FdActions,String> {
+    io::stdout().flush().unwrap();
+    let (actions, _files) = open_redirects(redirects)?;
+    let mut saved = Vec::new();
+    for (fd, source) in actions.iter() {
+        if is_internal(*fd) {
+            restore_shell(saved);
+            return Err(format!("{}: Bad file descriptor",fd));
+        }
+        saved.push((*fd,fcntl(*fd,FcntlArg::F_DUPFD_CLOEXEC(10)).ok()));
+        match source {
+            Some(source) => {
+                if let Err(e) = unistd::dup2(*source,*fd) {
+                    restore_shell(saved);
+                    return Err(format!("{}: {}",fd,e.desc()));
                 }
             },
-            _ => (),
+            None => {
+                let _ = unistd::close(*fd);
+            },
         }
-        
+    }
+    Ok(saved)
+}
 
-        match command.spawn() {
-            Ok(x) => processes.push(Some(x)),
-            Err(_) => {
-                eprintln!("{}: Command not found", cmds[i]);
-                return None;
+fn restore_shell(saved: FdActions) {
+    io::stdout().flush().unwrap();
+    for (fd, copy) in saved.into_iter().rev() {
+        match copy {
+            Some(copy) => {
+                unistd::dup2(copy,fd).unwrap();
+                unistd::close(copy).unwrap();
+            },
+            None => {
+                let _ = unistd::close(fd);
             },
         }
-        if unsafe { VERBOSE == 1 } {
-            match &processes[i] {
-                Some(proc) => println!("pid child = {}", proc.id()),
-                None => (),
-            } 
+    }
+}
+
+// applies the redirections in a child between fork and exec, so it can't
+// allocate
+fn apply_redirects(actions: &[(i32,Option<i32>)]) -> io::Result<()> {
+    for (fd, source) in actions.iter() {
+        match source {
+            Some(source) => {
+                unistd::dup2(*source,*fd)?;
+            },
+            None => {
+                let _ = unistd::close(*fd);
+            },
         }
-        
+    }
+    Ok(())
+}
 
-        if i == 0 {
-            match &processes[i] {
-                Some(proc) => {
-                    group_id = proc.id().try_into().unwrap();
-                    pids.push(group_id);
-                },
-                None => pids.push(0),
-            }
+// runs a command in a copy of the shell with its stdout on a pipe and
+// returns what it printed, without the trailing newlines
+fn command_substitution(text: &str, aliases: &mut BTreeMap<String,(String,Vec<String>)>, variables: &mut BTreeMap<String, String>, functions: &mut Functions) -> String {
+    let (read_fd, write_fd) = match unistd::pipe2(OFlag::O_CLOEXEC) {
+        Ok(fds) => fds,
+        Err(e) => {
+            eprintln!("tsh: {}",e);
+            return String::new();
         }
-        else {
-            match &processes[i] {
-                Some(proc) => pids.push(proc.id().try_into().unwrap()),
-                None => pids.push(0),
+    };
+
+    let job_control = terminal::active();
+    let reaping = hold_reaping();
+    // it stays in the shell's process group, so Ctrl-C reaches it, and
+    // Ctrl-Z must not stop it while the shell waits for its output
+    let child = match fork_shell(unistd::getpgrp().as_raw()) {
+        Ok(None) => {
+            if job_control {
+                terminal::ignore_stops();
             }
+            unistd::close(read_fd).unwrap();
+            unistd::dup2(write_fd,1).unwrap();
+            eval(text,aliases,variables,functions);
+            exit_shell(Some(last_status()));
+        },
+        Ok(Some(child)) => child,
+        Err(e) => {
+            eprintln!("tsh: fork: {}",e);
+            let _ = unistd::close(read_fd);
+            let _ = unistd::close(write_fd);
+            return String::new();
         }
+    };
+    if reaping.is_some() {
+        JOBS.lock().unwrap().add_substitution(child.as_raw(),true);
     }
-    
-    if !bg {
-        if unsafe { VERBOSE == 1 } {
-            println!("spawning in forground");
-        }
-        unsafe {
-            JOBS.addjob(&pids, pids[0], ProccessState::FG, cmdline);
-        } 
-        return waitfg(pids[0]);
+    drop(reaping);
+    unistd::close(write_fd).unwrap();
+
+    let mut file = unsafe { File::from_raw_fd(read_fd) };
+    let mut output = Vec::new();
+    let _ = file.read_to_end(&mut output);
+    drop(file);
 
+    let status = if SUBSHELL.load(Ordering::Relaxed) {
+        wait_child(child)
     }
-    else if bg {
-        if unsafe { VERBOSE == 1 } {
-            println!("spawning in background");
+    else {
+        // the signal thread reaps it and keeps its status for us
+        let mut reaping = REAPING.lock().unwrap();
+        loop {
+            if let Some(status) = JOBS.lock().unwrap().take_status(child.as_raw()) {
+                break Some(status);
+            }
+            reaping = JOB_CHANGED.wait(reaping).unwrap();
         }
+    };
+    set_status(status);
 
-        unsafe {
-            JOBS.addjob(&pids, pids[0], ProccessState::BG, cmdline);
-        } 
-        return None;
+    let mut output = String::from_utf8_lossy(&output).into_owned();
+    while output.ends_with('\n') {
+        output.pop();
     }
+    output
+}
 
-    return None;
-
+// runs a command with its stdout, or for >(...) its stdin, on a pipe and
+// returns a /dev/fd path to the shell's end, which commands inherit
+pub fn process_substitution(text: &str, output: bool, aliases: &mut BTreeMap<String,(String,Vec<String>)>, variables: &mut BTreeMap<String, String>, functions: &mut Functions) -> Result<String,String> {
+    let (read_fd, write_fd) = unistd::pipe2(OFlag::O_CLOEXEC).map_err(|e| e.desc().to_string())?;
+    let (child_fd, shell_fd, target) = if output { (read_fd,write_fd,0) } else { (write_fd,read_fd,1) };
+
+    io::stdout().flush().unwrap();
+    let _reaping = hold_reaping();
+    match fork_shell(0) {
+        Ok(None) => {
+            // the command must see end of file once the shell's end closes
+            unistd::close(shell_fd).unwrap();
+            close_process_fds(0);
+            unistd::dup2(child_fd,target).unwrap();
+            eval(text,aliases,variables,functions);
+            exit_shell(Some(last_status()));
+        },
+        Ok(Some(child)) => JOBS.lock().unwrap().add_substitution(child.as_raw(),false),
+        Err(e) => {
+            let _ = unistd::close(read_fd);
+            let _ = unistd::close(write_fd);
+            return Err(format!("fork: {}",e));
+        }
+    }
+    unistd::close(child_fd).unwrap();
+
+    // kept clear of the descriptors redirections tend to use
+    let fd = fcntl(shell_fd,FcntlArg::F_DUPFD(60));
+    unistd::close(shell_fd).unwrap();
+    let fd = fd.map_err(|e| e.desc().to_string())?;
+    PROCESS_FDS.lock().unwrap().push(fd);
+    Ok(format!("/dev/fd/{}",fd))
+}
 
+// closes the process substitution pipes opened after the first count
+fn close_process_fds(count: usize) {
+    let mut fds = PROCESS_FDS.lock().unwrap();
+    let start = count.min(fds.len());
+    for fd in fds.drain(start..) {
+        let _ = unistd::close(fd);
+    }
 }
 
-fn parseline(cmdline: &str) -> (bool,Vec<String>, bool) {
-    if unsafe { VERBOSE == 1 } {
-        println!("Parseline");
-    }
-    let mut argv: Vec<String> = Vec::new();
-    let bg: bool;
-    let mut append: bool = false;
-    let mut array = cmdline.to_string(); 
-    /*if array.contains("\n") {
-        array.pop();
-    }*/ 
-    //array.push(' ');
-    let result = cmdline.rfind("&");
-    if result != None && cmdline.get(result.unwrap()-1..=result.unwrap()) != Some("&&"){ 
-        if unsafe { VERBOSE == 1} {
-            println!("{:?}",cmdline.get(result.unwrap()-1..=result.unwrap()));
-        }
-        bg = true;
+fn create_subproccesses(cmdline: &str, pipeline: &Pipeline, bg: bool, aliases: &mut BTreeMap<String,(String,Vec<String>)>, variables: &mut BTreeMap<String, String>, functions: &mut Functions) -> Option<i32> {
+
+    // a compound command on its own runs in the shell itself
+    if pipeline.commands.len() == 1 && !bg && is_compound(pipeline) {
+        return run_compound(cmdline,&pipeline.commands[0],aliases,variables,functions);
     }
-    else {
-        bg = false;
-    }
-
-    while array.len() != 0 {
-        //println!("{}",array);
-        //println!("array len: {}", array.len());
-        match array.get(..1).unwrap() {
-            "'" => {
-                        let mut temp: String = array.drain(..1).collect();
-                        //println!("{}!", array);
-                        let temp2: String = array.drain(..array.find('\'').unwrap()+1).collect();
-                        temp += &temp2;
-
-                        argv.push(temp);
-                   },
-            " " => {
-                        //println!("Space");
-                        argv.push(array.drain(..1).collect());
-                   },
-            "|" => {
-                        //println!("Space");
-                        match array.get(..2) {
-                            Some("||") => {
-                                argv.push(array.drain(..2).collect());
-                            },
-                            _ => {
-                                argv.push(array.drain(..1).collect());
-                            },
-                        }
-                   },
-            "<" => {
-                        //println!("Space");
-                        argv.push(array.drain(..1).collect());
-                   },
-            ">" => {
-                        //println!("Space");
-                        match array.get(..2) {
-                            Some(">>") => {
-                                append = true;
-                                argv.push(array.drain(..2).collect());
-                            },
-                            _ => {
-                                argv.push(array.drain(..1).collect());
-                            },
-                        }
-                   },
-            "=" => {
-                        //println!("Space");
-                        argv.push(array.drain(..1).collect());
-                   },
-            "&" => {
-                        //println!("Space");
-                        match array.get(..2) {
-                            Some("&&") => {
-                                argv.push(array.drain(..2).collect());
-                            },
-                            _ => {
-                                array.drain(..1);
-                            },
-                        }
-                   },
-            "\n" => {
-                        //println!("Space");
-                        array.drain(..1);
-                   },
-            _ => {
-                        //println!("Default");
-                        argv.push(array.drain(0..array.find(|c: char| c == '>' || c == '|' || c == '<' || c == ' ' || c == '=' || c == '\n').unwrap()).collect());
 
-                 } 
+    // compound commands in a pipeline are expanded later, in their own process
+    let mut cmds: Vec<Option<PreparedCommand>> = Vec::new();
+    for command in pipeline.commands.iter() {
+        let simple = match command {
+            AstCommand::Simple(simple) => simple,
+            _ => {
+                cmds.push(None);
+                continue;
+            },
+        };
+        match prepare_command(simple,aliases,variables,functions) {
+            Ok(cmd) => cmds.push(Some(cmd)),
+            Err(e) => {
+                eprintln!("tsh: {}",e);
+                set_status(Some(1));
+                return Some(1);
+            }
         }
+    }
 
-        //println!("{:?}",argv);
+    if verbose() {
+        println!("cmds {:?}",cmds);
+        println!("bg {:?}",bg);
 
+        println!("\npid = {}", process::id());
     }
 
+    if let (false, [Some(cmd)]) = (bg, cmds.as_slice()) {
+        let function = cmd.argv.first().and_then(|name| functions.get(name));
+        // NAME = value, as .rshrc files written for the old parser have it
+        let spaced_assignment = cmd.argv.len() == 3 && cmd.argv[1] == "=" && function.is_none() && !is_builtin(&cmd.argv[0]) && parser::is_name(&cmd.argv[0]);
+        if cmd.argv.is_empty() || function.is_some() || is_builtin(&cmd.argv[0]) || spaced_assignment {
+            let saved = match redirect_shell(&cmd.redirects) {
+                Ok(saved) => saved,
+                Err(e) => {
+                    eprintln!("tsh: {}",e);
+                    set_status(Some(1));
+                    return Some(1);
+                }
+            };
+            let status = if cmd.argv.is_empty() {
+                for (key, val) in cmd.env.iter() {
+                    builtin::variable(key,val,variables);
+                }
+                Some(0)
+            }
+            else if spaced_assignment {
+                builtin::variable(&cmd.argv[0],&cmd.argv[2],variables);
+                set_status(Some(0));
+                Some(0)
+            }
+            else if let Some(body) = function {
+                run_function(body,&cmd.argv,aliases,variables,functions)
+            }
+            else {
+                let status = builtin_cmd(&cmd.argv,aliases,variables,functions);
+                set_status(status);
+                status
+            };
+            restore_shell(saved);
+            return status;
+        }
+    }
 
-    return (bg,argv,append);
-}
-
-fn parseargs(argv: &Vec<String>,aliases: & BTreeMap<String,(String,Vec<String>)>, variables: &mut BTreeMap<String, String>) -> (Vec<String>,Vec<Vec<String>>,Vec<(String,String)>,Vec<Redirection<usize>>,Vec<Redirection<usize>>) {
-    if unsafe { VERBOSE == 1 } {
-        println!("parseargs");
-    }
-    let mut cmds: Vec<String> = Vec::new();
-    let mut args: Vec<Vec<String>> = Vec::new();
-    let mut env: Vec<(String,String)> = Vec::new();
-    let mut stdin_redir: Vec<Redirection<usize>> = Vec::new();
-    let mut stdout_redir: Vec<Redirection<usize>> = Vec::new();
-
-    let mut curr_cmd = 0;
-    cmds.push("".to_string());
-    args.push(Vec::new());
-    stdin_redir.push(Redirection::Normal);
-    stdout_redir.push(Redirection::Normal);
-
-    let mut skip = false;
-    for i in 0..argv.len() {
-        match argv[i].as_str() {
-            "|" => {
-                    stdout_redir[curr_cmd] = Redirection::Pipe;
-                    stdin_redir.push(Redirection::Pipe);
-                    stdout_redir.push(Redirection::Normal);
-                    cmds.push("".to_string());
-                    args.push(Vec::new());
-                    curr_cmd += 1;
-                },
-            "<" => {
-                    //stdin_redir[curr_cmd] = args[curr_cmd].len();
-                    stdin_redir[curr_cmd] = Redirection::File(i + 2);
-                    skip = true;
-                },
-            ">" => {
-                    //stdout_redir[curr_cmd] = args[curr_cmd].len();
-                    stdout_redir[curr_cmd] = Redirection::File(i + 2);
-                    skip = true;
+    let reaping = hold_reaping();
+    let mut pids: Vec<i32> = Vec::new();
+    // inside a forked shell everything stays in its process group
+    let subshell = SUBSHELL.load(Ordering::Relaxed);
+    let mut group_id = if subshell { unistd::getpgrp().as_raw() } else { 0 };
+    // the read end of the pipe from the previous command
+    let mut pipe_in: Option<File> = None;
+    // the status of a last command that couldn't be started, which the
+    // pipeline gives whatever the commands before it do
+    let mut failed = None;
+    for (i, cmd) in cmds.iter().enumerate() {
+        let stdin_pipe = pipe_in.take();
+        let mut stdout_pipe = None;
+        if i + 1 < cmds.len() {
+            match unistd::pipe2(OFlag::O_CLOEXEC) {
+                Ok((read_fd, write_fd)) => unsafe {
+                    pipe_in = Some(File::from_raw_fd(read_fd));
+                    stdout_pipe = Some(File::from_raw_fd(write_fd));
                 },
-            ">>" => {
-                    //stdout_redir[curr_cmd] = args[curr_cmd].len();
-                    stdout_redir[curr_cmd] = Redirection::File(i + 2);
-                    skip = true;
-                },
-            "=" => {
-                    skip = true;
-                },
-            " " => {
-                    
-                },
-            "&&" => {
-                    stdout_redir[curr_cmd] = Redirection::Normal;
-                    stdin_redir.push(Redirection::Normal);
-                    stdout_redir.push(Redirection::Normal);
-                    stdin_redir.push(Redirection::Normal);
-                    stdout_redir.push(Redirection::Normal);
-                    cmds.push("&&".to_string());
-                    args.push(Vec::new());
-                    cmds.push("".to_string());
-                    args.push(Vec::new());
-                    curr_cmd += 2;
-                },
-            "||" => {
-                    stdout_redir[curr_cmd] = Redirection::Normal;
-                    stdin_redir.push(Redirection::Normal);
-                    stdout_redir.push(Redirection::Normal);
-                    stdin_redir.push(Redirection::Normal);
-                    stdout_redir.push(Redirection::Normal);
-                    cmds.push("&&".to_string());
-                    args.push(Vec::new());
-                    cmds.push("".to_string());
-                    args.push(Vec::new());
-                    curr_cmd += 2;
-                },
-            _ => {
-                    if skip {
-                        skip = false;
-                        continue;
-                    }
+                Err(e) => {
+                    eprintln!("tsh: {}",e);
+                    set_status(Some(1));
+                    return Some(1);
+                }
+            }
+        }
 
-                    if i + 2 < argv.len() && argv[i+1].as_str() == "=" {
-                        let val;
-                        if argv[i+2].contains("'") {
-                            val = argv[i+2].clone().drain(1..argv[i].len()-1).collect();
+        // compound commands and functions in a pipeline run in a copy of the shell
+        let function = cmd.as_ref().and_then(|cmd| cmd.argv.first()).and_then(|name| functions.get(name));
+        let cmd = match cmd {
+            Some(cmd) if function.is_none() => cmd,
+            _ => {
+                match fork_shell(group_id) {
+                    Ok(None) => {
+                        if let Some(file) = stdin_pipe {
+                            unistd::dup2(file.as_raw_fd(),0).unwrap();
                         }
-                        else {
-                            val = argv[i+2].clone();
+                        if let Some(file) = stdout_pipe {
+                            unistd::dup2(file.as_raw_fd(),1).unwrap();
                         }
-                        env.push((argv[i].clone(),val));
-                        skip = true;
-                        if unsafe { VERBOSE == 1} {
-                            println!("env: {:?}",env);
+                        if let Some(Err(e)) = cmd.as_ref().map(|cmd| redirect_shell(&cmd.redirects)) {
+                            eprintln!("tsh: {}",e);
+                            exit_shell(Some(1));
                         }
-                        continue;
-                    }
-
-                    if cmds[curr_cmd].as_str() == "" {
-                        let cmd;
-                        match aliases.get(&argv[i]) {
-                            Some(val) => {
-                                cmd = val.0.clone();
-                                args[curr_cmd] = val.1.clone();
-                            },
-                            None =>  {
-
-                                if argv[i].get(..1) == Some("$") {
-                                    match env::var(argv[i].clone().drain(1..).collect::<String>()) {
-                                        Ok(val) => {
-                                            if val.contains(" ") {
-                                                let mut var:Vec<&str> = val.split(" ").collect();
-                                                cmd = var[0].to_string();
-                                                var.remove(0);
-                                                for arg in var.iter() {
-                                                    args[curr_cmd].push(arg.to_string());
-                                                } 
-                                            }
-                                            else {
-                                                cmd = val;
-                                            }
-                                        }
-                                        Err(_) => {
-                                            match variables.get(&argv[i].clone().drain(1..).collect::<String>()) {
-                                                Some(val) => {
-                                                    if val.contains(" ") {
-                                                        let mut var: Vec<&str> = val.split(" ").collect();
-                                                        cmd = var[0].to_string();
-                                                        var.remove(0);
-                                                        for arg in var.iter() {
-                                                            args[curr_cmd].push(arg.to_string());
-                                                        }
-                                                    }
-                                                    else {
-                                                        cmd = val.to_string();
-                                                    }
-                                                },
-                                                None => cmd = argv[i].as_str().to_string(),
-                                            }
-
-                                        },//cmd = argv[i].as_str().to_string(),
-                                    }
-                                }
-                                else {
-                                    cmd = argv[i].as_str().to_string();
-                                }
-
-                            },
+                        let status = match (cmd, function, &pipeline.commands[i]) {
+                            (Some(cmd), Some(body), _) => run_function(body,&cmd.argv,aliases,variables,functions),
+                            // already in a process of its own
+                            (_, _, AstCommand::Subshell(list)) => run_list(list,aliases,variables,functions),
+                            (_, _, command) => run_compound(cmdline,command,aliases,variables,functions),
+                        };
+                        exit_shell(status);
+                    },
+                    Ok(Some(child)) => {
+                        if group_id == 0 {
+                            group_id = child.as_raw();
                         }
-                        cmds[curr_cmd] = cmd;
-                    }
-                    else {
-                        if argv[i].get(..1) == Some("$") {
-
-                            match env::var(argv[i].clone().drain(1..).collect::<String>()) {
-                                Ok(val) => {
-                                    if val.contains(" ") {
-                                        let var:Vec<&str> = val.split(" ").collect();
-                                        for arg in var.iter() {
-                                            args[curr_cmd].push(arg.to_string());
-                                        } 
-                                    }
-                                    else {
-                                        args[curr_cmd].push(val);
-                                    }
-                                }
-                                Err(_) => {
-                                    match variables.get(&argv[i].clone().drain(1..).collect::<String>()) {
-                                        Some(val) => {
-                                            if val.contains(" ") {
-                                                let var: Vec<&str> = val.split(" ").collect();
-                                                for arg in var.iter() {
-                                                    args[curr_cmd].push(arg.to_string());
-                                                }
-                                            }
-                                            else {
-                                                args[curr_cmd].push(val.to_string());
-                                            }
-                                        },
-                                        None => args[curr_cmd].push(argv[i].as_str().to_string()),
-                                    }
-                                },//args[curr_cmd].push(argv[i].as_str().to_string()),
-                            }
+                        pids.push(child.as_raw());
+                    },
+                    Err(e) => eprintln!("tsh: fork: {}",e),
+                }
+                continue;
+            },
+        };
 
-                        }
-                        else {
-                            args[curr_cmd].push(argv[i].as_str().to_string());
-                        }
-                    }
+        if cmd.argv.is_empty() {
+            continue;
+        }
+
+        let last = i + 1 == cmds.len();
+        if !command_exists(&cmd.argv[0],&cmd.env) {
+            eprintln!("{}: Command not found", cmd.argv[0]);
+            set_status(Some(127));
+            if last {
+                failed = Some(127);
+            }
+            continue;
+        }
+
+        let mut command = Command::new(cmd.argv[0].as_str());
+        command.process_group(group_id);
+        command.args(&cmd.argv[1..]);
+
+        for (key, val) in cmd.env.iter() {
+            command.env(key,val);
+        }
+
+        if let Some(pipe) = stdin_pipe {
+            command.stdin(pipe);
+        }
+        if let Some(pipe) = stdout_pipe {
+            command.stdout(pipe);
+        }
+        // the files only have to stay open until the child has its copies
+        let (actions, _files) = match open_redirects(&cmd.redirects) {
+            Ok(opened) => opened,
+            Err(e) => {
+                eprintln!("tsh: {}",e);
+                set_status(Some(1));
+                continue;
+            }
+        };
+        unsafe {
+            command.pre_exec(move || {
+                terminal::enter_child(!bg);
+                Ok(())
+            });
+        }
+        if !actions.is_empty() {
+            unsafe {
+                command.pre_exec(move || apply_redirects(&actions));
+            }
+        }
+
+        let pid: i32 = match command.spawn() {
+            Ok(child) => child.id().try_into().unwrap(),
+            Err(e) => {
+                let status = if e.kind() == io::ErrorKind::NotFound { 127 } else { 126 };
+                if status == 127 {
+                    eprintln!("{}: Command not found", cmd.argv[0]);
+                }
+                else {
+                    eprintln!("tsh: {}: {}",cmd.argv[0],io_error(&e));
+                }
+                set_status(Some(status));
+                if last {
+                    failed = Some(status);
                 }
-        } 
+                continue;
+            },
+        };
+        if verbose() {
+            println!("pid child = {}", pid);
+        }
+
+        if group_id == 0 {
+            group_id = pid;
+        }
+        pids.push(pid);
+    }
+    drop(pipe_in);
+
+    if pids.is_empty() {
+        // a child that failed to exec may already have taken the terminal
+        if !bg {
+            let _ = terminal::take_back();
+        }
+        return Some(last_status());
+    }
+
+    // a forked shell has no signal thread reaping its children
+    if subshell {
+        if bg {
+            return None;
+        }
+        let mut status = None;
+        for pid in pids.iter() {
+            status = wait_child(Pid::from_raw(*pid));
+        }
+        let status = failed.or(status);
+        set_status(status);
+        return status;
+    }
+
+    if !bg {
+        if verbose() {
+            println!("spawning in forground");
+        }
+        JOBS.lock().unwrap().addjob(&pids, pids[0], ProccessState::FG, &job_text(cmdline));
+        drop(reaping);
+        let status = waitfg(pids[0]);
+        if failed.is_some() {
+            set_status(failed);
+            return failed;
+        }
+        status
 
     }
-    
+    else {
+        if verbose() {
+            println!("spawning in background");
+        }
+
+        JOBS.lock().unwrap().addjob(&pids, pids[0], ProccessState::BG, cmdline);
+        None
+    }
+
 
-    return (cmds,args,env,stdin_redir,stdout_redir);
 }
 
 
-pub fn do_bgfg(argv: &Vec<String>) {
-    if argv.len() == 1 {
-        println!("{} command requires PID or %jobid argument",argv[0]);
-        return;
+// whether a command can be found on PATH, or the command's own PATH, so a
+// missing one is reported without forking for it
+fn command_exists(name: &str, env: &[(String,String)]) -> bool {
+    if name.contains('/') {
+        return Path::new(name).exists();
+    }
+    let path = match env.iter().rev().find(|(key, _)| key == "PATH") {
+        Some((_, path)) => path.clone(),
+        None => env::var("PATH").unwrap_or_default(),
+    };
+    path.split(':').any(|dir| {
+        let dir = if dir.is_empty() { "." } else { dir };
+        Path::new(dir).join(name).is_file()
+    })
+}
+
+// the jid of the job a %jobspec or a pid names
+fn find_job(jobs: &mut Jobs, arg: &str) -> Result<u32,String> {
+    if let Some(spec) = arg.strip_prefix('%') {
+        return jobs.resolve(spec);
+    }
+    match arg.parse::<i32>() {
+        Ok(pid) => jobs.get_job_pid(pid).map(|job| job.jid).ok_or_else(|| format!("({}): No such process",pid)),
+        Err(_) => Err("argument must be a PID or %jobid".to_string()),
+    }
+}
+
+// returns the status of a job brought to the foreground once it is done.
+// without an argument both work on the current job
+pub fn do_bgfg(argv: &[String]) -> Option<i32> {
+    // the jobs a forked shell knows of are its parent's
+    if SUBSHELL.load(Ordering::Relaxed) {
+        eprintln!("{}: no job control",argv[0]);
+        return Some(1);
     }
+    let arg = argv.get(1).map(|arg| arg.as_str()).unwrap_or("%+");
+
+    let mut jobs = JOBS.lock().unwrap();
+    let jid = match find_job(&mut jobs,arg) {
+        Ok(jid) => jid,
+        Err(e) => {
+            eprintln!("{}: {}",argv[0],e);
+            return Some(1);
+        },
+    };
 
     if argv[0].as_str() == "fg" {
-        let result;
-        let num;
-        let mut jid = false;
-        if argv[1].find("%") != None {
-            jid = true;
-            result = argv[1].clone().drain(1..).collect::<String>().parse::<u32>();
+        let job = jobs.get_job_jid(jid).unwrap();
+        job.resume(ProccessState::FG);
+        let (pgid, modes) = (job.pgid,job.modes.take());
+        drop(jobs);
+        terminal::give(pgid,modes.as_ref());
+        let _ = signal::kill(Pid::from_raw(-pgid),Signal::SIGCONT);
+
+        return waitfg(pgid);
+    }
+
+    let job = jobs.get_job_jid(jid).unwrap();
+    job.resume(ProccessState::BG);
+    let pgid = job.pgid;
+    jobs.touch(jid);
+    let _ = signal::kill(Pid::from_raw(-pgid),Signal::SIGCONT);
+    Some(0)
+}
+
+// kill [-s sig | -n num | -sig] pid|%jobspec ..., or kill -l
+fn kill_builtin(argv: &[String]) -> i32 {
+    let mut signal = Signal::SIGTERM;
+    let mut args = &argv[1..];
+    match args.first().map(|arg| arg.as_str()) {
+        None => {
+            eprintln!("kill: usage: kill [-s sigspec | -n signum | -sigspec] pid | jobspec ...");
+            return 2;
+        },
+        Some("-l") => {
+            for signal in Signal::iterator() {
+                println!("{:2}) {}",signal as i32,signal);
+            }
+            return 0;
+        },
+        Some("-s") | Some("-n") if args.len() > 1 => {
+            signal = match parse_signal(&args[1]) {
+                Some(signal) => signal,
+                None => {
+                    eprintln!("kill: {}: invalid signal specification",args[1]);
+                    return 1;
+                },
+            };
+            args = &args[2..];
+        },
+        Some(arg) if arg.starts_with('-') && arg != "--" => {
+            signal = match parse_signal(&arg[1..]) {
+                Some(signal) => signal,
+                None => {
+                    eprintln!("kill: {}: invalid signal specification",&arg[1..]);
+                    return 1;
+                },
+            };
+            args = &args[1..];
+        },
+        _ => (),
+    }
+    if args.first().is_some_and(|arg| arg == "--") {
+        args = &args[1..];
+    }
+
+    let mut status = 0;
+    for arg in args {
+        let target = if arg.starts_with('%') {
+            let mut jobs = JOBS.lock().unwrap();
+            match find_job(&mut jobs,arg) {
+                // a stopped job has to be woken to act on the signal
+                Ok(jid) => {
+                    let job = jobs.get_job_jid(jid).unwrap();
+                    Ok((-job.pgid,matches!(job.state, ProccessState::ST)))
+                },
+                Err(e) => Err(e),
+            }
         }
         else {
-            result = argv[1].clone().drain(..).collect::<String>().parse::<u32>();
-        }
+            match arg.parse::<i32>() {
+                Ok(pid) => Ok((pid,false)),
+                Err(_) => Err(format!("{}: arguments must be process or job IDs",arg)),
+            }
+        };
 
-        match result {
-            Ok(val) => num = val,
-            Err(_) => {
-                eprintln!("{}: argument must be a PID or %jobid",argv[0]);
-                return;
+        match target {
+            Ok((pid, stopped)) => {
+                if let Err(e) = signal::kill(Pid::from_raw(pid),signal) {
+                    eprintln!("kill: ({}) - {}",pid.abs(),e.desc());
+                    status = 1;
+                }
+                else if stopped && signal != Signal::SIGCONT {
+                    let _ = signal::kill(Pid::from_raw(pid),Signal::SIGCONT);
+                }
+            },
+            Err(e) => {
+                eprintln!("kill: {}",e);
+                status = 1;
             },
         }
-        
-        let job: Option<&mut Job>;
-        if jid {
-            job = unsafe { JOBS.get_job_jid(num) };
-        }
-        else {
-            job = unsafe { JOBS.get_job_pid(num as i32) };
-        }
+    }
+    status
+}
 
-        match job {
-            Some(job) => {
-                job.state = ProccessState::FG;
-                signal::kill(Pid::from_raw(-job.pgid),Signal::SIGCONT).unwrap();
+// a signal by number or by name, with or without SIG
+fn parse_signal(name: &str) -> Option<Signal> {
+    if let Ok(num) = name.parse::<i32>() {
+        return Signal::try_from(num).ok();
+    }
+    let name = name.to_uppercase();
+    if name.starts_with("SIG") {
+        name.parse::<Signal>().ok()
+    }
+    else {
+        format!("SIG{}",name).parse::<Signal>().ok()
+    }
+}
 
-                waitfg(job.pgid);
-            },
-            None => {
-                if jid {
-                    eprintln!("%{}: No such job",num);
-                }
-                else {
-                    eprintln!("({}): No such process",num);
-                }
+// wait [pid|%jobspec ...]. waits for every background job without
+// arguments, otherwise for each one named, and gives the status of the last
+fn wait_builtin(argv: &[String]) -> i32 {
+    if SUBSHELL.load(Ordering::Relaxed) {
+        return wait_children(argv);
+    }
+    if argv.len() == 1 {
+        let mut reaping = REAPING.lock().unwrap();
+        while JOBS.lock().unwrap().iter().any(|job| matches!(job.state, ProccessState::BG)) {
+            if INTERRUPTED.load(Ordering::Relaxed) {
+                return 130;
             }
+            reaping = JOB_CHANGED.wait(reaping).unwrap();
         }
+        return 0;
     }
-    else if argv[0].as_str() == "bg" {
-        let result;
-        let num;
-        let mut jid = false;
-        if argv[1].find("%") != None {
-            jid = true;
-            result = argv[1].clone().drain(1..).collect::<String>().parse::<u32>();
+
+    let mut status = 0;
+    for arg in &argv[1..] {
+        let pgid = {
+            let mut jobs = JOBS.lock().unwrap();
+            match find_job(&mut jobs,arg) {
+                Ok(jid) => {
+                    let job = jobs.get_job_jid(jid).unwrap();
+                    job.waited = true;
+                    job.pgid
+                },
+                Err(e) => {
+                    eprintln!("wait: {}",e);
+                    status = 127;
+                    continue;
+                },
+            }
+        };
+
+        let mut reaping = REAPING.lock().unwrap();
+        // a stopped job won't finish until it is continued
+        while JOBS.lock().unwrap().get_job_pid(pgid).is_some_and(|job| !matches!(job.state, ProccessState::ST)) {
+            if INTERRUPTED.load(Ordering::Relaxed) {
+                if let Some(job) = JOBS.lock().unwrap().get_job_pid(pgid) {
+                    job.waited = false;
+                }
+                return 130;
+            }
+            reaping = JOB_CHANGED.wait(reaping).unwrap();
         }
-        else {
-            result = argv[1].clone().drain(..).collect::<String>().parse::<u32>();
+        drop(reaping);
+
+        let mut jobs = JOBS.lock().unwrap();
+        if let Some(job) = jobs.get_job_pid(pgid) {
+            job.waited = false;
         }
+        status = jobs.take_status(pgid).or_else(|| jobs.get_job_pid(pgid).and_then(|job| job.stop_signal()).map(|signal| 128 + signal as i32)).unwrap_or(0);
+    }
+    status
+}
 
-        match result {
-            Ok(val) => num = val,
+// a forked shell's background jobs aren't in the job table and nothing else
+// reaps them, so it waits for its children itself
+fn wait_children(argv: &[String]) -> i32 {
+    if argv.len() == 1 {
+        while wait::waitpid(None,None) != Err(Errno::ECHILD) {}
+        return 0;
+    }
+
+    let mut status = 0;
+    for arg in &argv[1..] {
+        status = match arg.parse::<i32>() {
+            Ok(pid) => wait_child(Pid::from_raw(pid)).unwrap_or_else(|| {
+                eprintln!("wait: pid {} is not a child of this shell",pid);
+                127
+            }),
             Err(_) => {
-                eprintln!("{}: argument must be a PID or %jobid",argv[0]);
-                return;
+                eprintln!("wait: {}: no job control",arg);
+                127
             },
+        };
+    }
+    status
+}
+
+// disown [-a] [pid|%jobspec ...] forgets jobs, the current one by default
+fn disown(argv: &[String]) -> i32 {
+    let mut jobs = JOBS.lock().unwrap();
+    if argv.get(1).is_some_and(|arg| arg == "-a") {
+        let jids: Vec<u32> = jobs.iter().map(|job| job.jid).collect();
+        for jid in jids {
+            jobs.remove(jid);
         }
-        
-        let job: Option<&mut Job>;
-        if jid {
-            job = unsafe { JOBS.get_job_jid(num) };
-        }
-        else {
-            job = unsafe { JOBS.get_job_pid(num as i32) };
-        }
+        return 0;
+    }
 
-        match job {
-            Some(job) => {
-                job.state = ProccessState::BG;
-                signal::kill(Pid::from_raw(-job.pgid),Signal::SIGCONT).unwrap();
+    let current = ["%+".to_string()];
+    let args = if argv.len() == 1 { &current[..] } else { &argv[1..] };
+    let mut status = 0;
+    for arg in args {
+        match find_job(&mut jobs,arg) {
+            Ok(jid) => {
+                jobs.remove(jid);
+            },
+            Err(e) => {
+                eprintln!("disown: {}",e);
+                status = 1;
             },
-            None => {
-                if jid {
-                    eprintln!("%{}: No such job",num);
-                }
-                else {
-                    eprintln!("({}): No such process",num);
-                }
-            }
         }
     }
+    status
 }
 
 fn waitfg(pid: i32) -> Option<i32> {
-    let mut counter = 0;
+    terminal::give(pid,None);
+    // the signal thread changes jobs with REAPING held and then wakes us, so
+    // the check and the wait can't miss a change in between
+    let mut reaping = REAPING.lock().unwrap();
     loop {
-        let job = unsafe {JOBS.get_job_pid(pid)};
-       
-        match job {
-            Some(x) => match x.state {
-                ProccessState::FG => {
-                    if counter == 1 {
-                        if unsafe { VERBOSE == 1} {
-                            println!("{}", x);
-                            println!("Pausing waitfg")
-                        }
-                        //pause(); 
-
-                        //let flags: wait::WaitPidFlag = wait::WaitPidFlag::WNOHANG | wait::WaitPidFlag::WUNTRACED;
-                        //pause(); 
-                        //waitpid(Pid::from_raw(-pid),Some(flags))
-
-                    }
-                }, 
-                _ => break
-            }
-            None => break,
+        match JOBS.lock().unwrap().get_job_pid(pid) {
+            Some(job) if matches!(job.state, ProccessState::FG) => {
+                if verbose() {
+                    println!("{}", job);
+                    println!("Pausing waitfg")
+                }
+            },
+            _ => break,
         }
-        counter += 1
+        reaping = JOB_CHANGED.wait(reaping).unwrap();
     }
+    drop(reaping);
 
-    if unsafe { VERBOSE == 1} {
+    if verbose() {
         println!("Broke out");
     }
 
-    return unsafe { EXITSTATUS };
-}
+    // a stopped job gets its terminal modes back when it is continued
+    let modes = terminal::take_back();
+    let mut jobs = JOBS.lock().unwrap();
+    let stopped = jobs.get_job_pid(pid).map(|job| {
+        job.modes = modes;
+        job.pgid
+    });
 
+    // a stopped job gives 128 and the signal, as one killed by it would
+    let status = jobs.take_status(pid).or_else(|| jobs.get_job_pid(pid).and_then(|job| job.stop_signal()).map(|signal| 128 + signal as i32));
+    drop(jobs);
+    let in_group = GROUP_TEXT.lock().unwrap().is_some();
+    if let (Some(pgid), true) = (stopped, in_group) {
+        if let Some(status) = stop_group(pgid) {
+            set_status(Some(status));
+            return Some(status);
+        }
+    }
+    set_status(status);
+    status
+}
 
-fn builtin_cmd(argv: &Vec<String>,aliases: &mut BTreeMap<String,(String,Vec<String>)>, variables: &mut BTreeMap<String, String>) -> i32 {
-    if argv.len() == 0 {
-        return 1;
-    } 
-    match argv[0].as_str() {
-        " " => return 1,
-        "" => return 1,
-        "quit" => process::exit(0),
-        "exit" => process::exit(0),
-        "jobs" => {
-            unsafe { print!("{}",JOBS);}
-            io::stdout().flush().unwrap();
-            return 1;
+// a command stopping in a { ...; } group stops the rest of it too. a copy of
+// the shell joins the command's job and goes on with the group once the
+// command is done, so that fg and bg carry on with all of it. returns the
+// command's status inside the copy, None in the shell
+fn stop_group(pgid: i32) -> Option<i32> {
+    GROUP_STOPPED.store(true,Ordering::Relaxed);
+    let (read_fd, write_fd) = match unistd::pipe2(OFlag::O_CLOEXEC) {
+        Ok(fds) => fds,
+        Err(e) => {
+            eprintln!("tsh: pipe: {}",e);
+            return None;
         },
-        "fg" => {
-            do_bgfg(&argv);
-            return 1;
+    };
+    let _reaping = hold_reaping();
+    match fork_shell(pgid) {
+        Ok(None) => {
+            GROUP_STOPPED.store(false,Ordering::Relaxed);
+            GROUP_REST.store(true,Ordering::Relaxed);
+            let _ = unistd::close(write_fd);
+            let mut pipe = unsafe { File::from_raw_fd(read_fd) };
+            let mut code = [0; 4];
+            if pipe.read_exact(&mut code).is_err() {
+                exit_shell(None);
+            }
+            Some(i32::from_ne_bytes(code))
         },
-        "bg" => {
-            do_bgfg(&argv);
-            return 1;
+        Ok(Some(child)) => {
+            let _ = unistd::close(read_fd);
+            let pipe = unsafe { File::from_raw_fd(write_fd) };
+            if !JOBS.lock().unwrap().add_rest(pgid,child.as_raw(),pipe) {
+                let _ = signal::kill(child,Signal::SIGKILL);
+            }
+            None
         },
-        "cd" => {
-            builtin::change_dir(argv);
-            return 1;
+        Err(e) => {
+            eprintln!("tsh: fork: {}",e);
+            let _ = unistd::close(read_fd);
+            let _ = unistd::close(write_fd);
+            None
         },
-        "alias" => {
-            builtin::alias(argv, aliases);
-            return 1;
-        }
-        "export" => {
-            if argv.len() == 5 {
-                builtin::export(argv);
-            }
-            return 1;
+    }
+}
+
+
+const BUILTINS: [&str; 19] = ["quit","exit","jobs","fg","bg","kill","wait","disown","cd","alias","export","vars","let","shopt","set","break","continue","return","local"];
+
+fn is_builtin(name: &str) -> bool {
+    BUILTINS.contains(&name)
+}
+
+fn builtin_cmd(argv: &[String],aliases: &mut BTreeMap<String,(String,Vec<String>)>, variables: &mut BTreeMap<String, String>, functions: &mut Functions) -> Option<i32> {
+    if argv.is_empty() {
+        return Some(0);
+    }
+    match argv[0].as_str() {
+        "quit" => exit_shell(Some(0)),
+        "exit" => exit_shell(Some(0)),
+        "jobs" => {
+            print!("{}",JOBS.lock().unwrap());
+            io::stdout().flush().unwrap();
+            Some(0)
         },
+        "fg" | "bg" => Some(do_bgfg(argv).unwrap_or(0)),
+        "kill" => Some(kill_builtin(argv)),
+        "wait" => Some(wait_builtin(argv)),
+        "disown" => Some(disown(argv)),
+        "cd" => Some(builtin::change_dir(argv)),
+        "alias" => Some(builtin::alias(argv, aliases)),
+        "export" => Some(builtin::export(argv,variables)),
         "vars" => {
             builtin::print_vars(variables);
-            return 1;
+            Some(0)
         },
-        _ => {
-            if argv.len() == 3 && argv[1].as_str() == "=" {
-                builtin::variable(argv,variables);
-                return 1;
-            }
-        }    
+        "let" => Some(builtin::let_expr(argv,variables)),
+        "shopt" => Some(builtin::shopt(argv)),
+        "set" => Some(builtin::set(argv)),
+        "break" | "continue" => Some(loop_control(argv)),
+        "return" => Some(return_builtin(argv,functions)),
+        "local" => Some(builtin::local(argv,variables,functions)),
+        _ => None,
     }
-    
-    return 0;
 
 }
 
@@ -1039,3 +1755,26 @@ fn usage() {
     process::exit(1);
 
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a forked shell waits for its own children, so these run without the
+    // signal thread
+    fn status_of(cmdline: &str) -> i32 {
+        SUBSHELL.store(true,Ordering::Relaxed);
+        eval(cmdline,&mut BTreeMap::new(),&mut BTreeMap::new(),&mut Functions::new());
+        last_status()
+    }
+
+    #[test]
+    fn missing_command() {
+        assert_eq!(status_of("tsh_test_no_such_command"),127);
+        // the last command decides the status of a pipeline, even one that
+        // never started
+        assert_eq!(status_of("true | tsh_test_no_such_command"),127);
+        assert_eq!(status_of("tsh_test_no_such_command | true"),0);
+        assert_eq!(status_of("PATH=/nonexistent true"),127);
+    }
+}
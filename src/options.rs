@@ -0,0 +1,47 @@
+use std::sync::atomic::{AtomicBool,Ordering};
+
+// options changed with shopt
+pub static DOTGLOB: AtomicBool = AtomicBool::new(false);
+pub static FAILGLOB: AtomicBool = AtomicBool::new(false);
+pub static GLOBSTAR: AtomicBool = AtomicBool::new(false);
+pub static NULLGLOB: AtomicBool = AtomicBool::new(false);
+
+// options changed with set
+pub static NOCLOBBER: AtomicBool = AtomicBool::new(false);
+
+pub const SHOPT_NAMES: [&str; 4] = ["dotglob","failglob","globstar","nullglob"];
+
+pub fn shopt_option(name: &str) -> Option<&'static AtomicBool> {
+    match name {
+        "dotglob" => Some(&DOTGLOB),
+        "failglob" => Some(&FAILGLOB),
+        "globstar" => Some(&GLOBSTAR),
+        "nullglob" => Some(&NULLGLOB),
+        _ => None,
+    }
+}
+
+pub const SET_NAMES: [&str; 1] = ["noclobber"];
+
+pub fn set_option(name: &str) -> Option<&'static AtomicBool> {
+    match name {
+        "noclobber" => Some(&NOCLOBBER),
+        _ => None,
+    }
+}
+
+// the single letter forms, as in set -C
+pub fn set_flag(flag: char) -> Option<&'static AtomicBool> {
+    match flag {
+        'C' => Some(&NOCLOBBER),
+        _ => None,
+    }
+}
+
+pub fn enabled(option: &AtomicBool) -> bool {
+    option.load(Ordering::Relaxed)
+}
+
+pub fn set(option: &AtomicBool, value: bool) {
+    option.store(value,Ordering::Relaxed);
+}
@@ -0,0 +1,793 @@
+use crate::lexer::{Lexer,SyntaxError,Token,Word,WordPart};
+
+// < > >| >> <> open the target, <& >& duplicate or close a descriptor,
+// &> &>> send both standard output and error to the target and << <<<
+// read a here-document or here-string
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum RedirOp {
+    Input,
+    Output,
+    Clobber,
+    Append,
+    ReadWrite,
+    DupInput,
+    DupOutput,
+    OutputAll,
+    AppendAll,
+    HereDoc,
+    HereString,
+}
+
+impl RedirOp {
+    // the descriptor used when the redirection doesn't name one
+    pub fn default_fd(self) -> i32 {
+        match self {
+            RedirOp::Input | RedirOp::ReadWrite | RedirOp::DupInput | RedirOp::HereDoc | RedirOp::HereString => 0,
+            _ => 1,
+        }
+    }
+}
+
+#[derive(Debug,Clone)]
+pub struct Redirect {
+    pub fd: Option<i32>,
+    pub op: RedirOp,
+    pub target: Word,
+}
+
+fn redirect_op(token: &Token) -> Option<RedirOp> {
+    match token {
+        Token::Less => Some(RedirOp::Input),
+        Token::Great => Some(RedirOp::Output),
+        Token::Clobber => Some(RedirOp::Clobber),
+        Token::DGreat => Some(RedirOp::Append),
+        Token::LessGreat => Some(RedirOp::ReadWrite),
+        Token::LessAnd => Some(RedirOp::DupInput),
+        Token::GreatAnd => Some(RedirOp::DupOutput),
+        Token::AndGreat => Some(RedirOp::OutputAll),
+        Token::AndDGreat => Some(RedirOp::AppendAll),
+        Token::DLess | Token::DLessDash => Some(RedirOp::HereDoc),
+        Token::TLess => Some(RedirOp::HereString),
+        _ => None,
+    }
+}
+
+// a |& b is a 2>&1 | b, after any redirections of a itself
+fn add_stderr_redirect(command: &mut Command) {
+    let redirect = Redirect {fd: Some(2), op: RedirOp::DupOutput, target: Word {parts: vec![WordPart::Literal("1".to_string())]}};
+    match command {
+        Command::Simple(simple) => simple.redirects.push(redirect),
+        Command::Redirected(_, redirects) => redirects.push(redirect),
+        _ => {
+            let inner = std::mem::replace(command,Command::Simple(SimpleCommand::default()));
+            *command = Command::Redirected(Box::new(inner),vec![redirect]);
+        },
+    }
+}
+
+#[derive(Debug,Clone)]
+pub struct Assignment {
+    pub name: String,
+    pub value: Word,
+}
+
+#[derive(Debug,Clone,Default)]
+pub struct SimpleCommand {
+    pub assignments: Vec<Assignment>,
+    pub words: Vec<Word>,
+    pub redirects: Vec<Redirect>,
+}
+
+// if/elif branches are (condition, body) pairs
+#[derive(Debug,Clone)]
+pub struct IfClause {
+    pub branches: Vec<(List,List)>,
+    pub else_branch: Option<List>,
+}
+
+// while, or until when until is set
+#[derive(Debug,Clone)]
+pub struct LoopClause {
+    pub until: bool,
+    pub condition: List,
+    pub body: List,
+}
+
+// for name in words, words is None without the in
+#[derive(Debug,Clone)]
+pub struct ForClause {
+    pub name: String,
+    pub words: Option<Vec<Word>>,
+    pub body: List,
+}
+
+// for ((init; condition; step))
+#[derive(Debug,Clone)]
+pub struct ArithForClause {
+    pub init: Word,
+    pub condition: Word,
+    pub step: Word,
+    pub body: List,
+}
+
+// what follows a case item: ;; stops, ;& runs the next body too and ;;&
+// goes on testing the patterns after it
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum CaseTerminator {
+    Break,
+    FallThrough,
+    Continue,
+}
+
+#[derive(Debug,Clone)]
+pub struct CaseItem {
+    pub patterns: Vec<Word>,
+    pub body: List,
+    pub terminator: CaseTerminator,
+}
+
+#[derive(Debug,Clone)]
+pub struct CaseClause {
+    pub word: Word,
+    pub items: Vec<CaseItem>,
+}
+
+#[derive(Debug,Clone)]
+pub struct FunctionDef {
+    pub name: String,
+    pub body: Box<Command>,
+}
+
+#[derive(Debug,Clone)]
+pub enum Command {
+    Simple(SimpleCommand),
+    Group(List),
+    Subshell(List),
+    Function(FunctionDef),
+    // a compound command with redirections for all of it
+    Redirected(Box<Command>,Vec<Redirect>),
+    If(IfClause),
+    Loop(LoopClause),
+    For(ForClause),
+    ArithFor(ArithForClause),
+    Case(CaseClause),
+}
+
+#[derive(Debug,Clone)]
+pub struct Pipeline {
+    pub commands: Vec<Command>,
+    // set by a leading !
+    pub negated: bool,
+}
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum Connector {
+    And,
+    Or,
+}
+
+#[derive(Debug,Clone)]
+pub struct AndOr {
+    pub first: Pipeline,
+    pub rest: Vec<(Connector,Pipeline)>,
+}
+
+// one element of a list, text is its source as shown by jobs
+#[derive(Debug,Clone)]
+pub struct ListItem {
+    pub and_or: AndOr,
+    pub bg: bool,
+    pub text: String,
+}
+
+#[derive(Debug,Clone,Default)]
+pub struct List {
+    pub items: Vec<ListItem>,
+}
+
+pub fn is_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if c == '_' || c.is_ascii_alphabetic() => (),
+        _ => return false,
+    }
+    chars.all(|c| c == '_' || c.is_ascii_alphanumeric())
+}
+
+// splits NAME=value into an assignment, the name has to be unquoted
+fn split_assignment(word: &Word) -> Option<Assignment> {
+    let first = match word.parts.first() {
+        Some(WordPart::Literal(text)) => text,
+        _ => return None,
+    };
+    let eq = first.find('=')?;
+    if !is_name(&first[..eq]) {
+        return None;
+    }
+
+    let mut value = Word::default();
+    if eq + 1 < first.len() {
+        value.parts.push(WordPart::Literal(first[eq + 1..].to_string()));
+    }
+    value.parts.extend(word.parts[1..].iter().cloned());
+
+    Some(Assignment {name: first[..eq].to_string(), value})
+}
+
+// reserved words that end the list before them
+const LIST_TERMINATORS: [&str; 8] = ["then","elif","else","fi","do","done","esac","}"];
+
+// splits the inside of for ((...)) at its semicolons
+fn split_arith_for(parts: Vec<WordPart>) -> Vec<Word> {
+    let mut words = vec![Word::default()];
+    for part in parts {
+        let text = match part {
+            WordPart::Literal(text) => text,
+            part => {
+                words.last_mut().unwrap().parts.push(part);
+                continue;
+            },
+        };
+        for (i, piece) in text.split(';').enumerate() {
+            if i > 0 {
+                words.push(Word::default());
+            }
+            if !piece.trim().is_empty() {
+                words.last_mut().unwrap().parts.push(WordPart::Literal(piece.to_string()));
+            }
+        }
+    }
+    words
+}
+
+fn unexpected(token: Token) -> SyntaxError {
+    match token {
+        Token::Eof => SyntaxError::Incomplete,
+        token => SyntaxError::Unexpected(token.to_string()),
+    }
+}
+
+pub struct Parser {
+    lexer: Lexer,
+    peeked: Option<Token>,
+}
+
+impl Parser {
+    pub fn new(input: &str) -> Self {
+        Self {lexer: Lexer::new(input), peeked: None}
+    }
+
+    fn peek(&mut self) -> Result<&Token,SyntaxError> {
+        if self.peeked.is_none() {
+            self.peeked = Some(self.lexer.next_token()?);
+        }
+        Ok(self.peeked.as_ref().unwrap())
+    }
+
+    fn next(&mut self) -> Result<Token,SyntaxError> {
+        match self.peeked.take() {
+            Some(token) => Ok(token),
+            None => self.lexer.next_token(),
+        }
+    }
+
+    fn skip_newlines(&mut self) -> Result<(),SyntaxError> {
+        while *self.peek()? == Token::Newline {
+            self.next()?;
+        }
+        Ok(())
+    }
+
+    pub fn parse_program(&mut self) -> Result<List,SyntaxError> {
+        self.parse_until(Token::Eof)
+    }
+
+    // parses a list that runs up to the given token, which is consumed
+    fn parse_until(&mut self, end: Token) -> Result<List,SyntaxError> {
+        let list = self.parse_list()?;
+        match self.next()? {
+            token if token == end => Ok(list),
+            token => Err(unexpected(token)),
+        }
+    }
+
+    fn at_list_end(&mut self) -> Result<bool,SyntaxError> {
+        Ok(match self.peek()? {
+            Token::Eof | Token::RParen | Token::DSemi | Token::SemiAmp | Token::DSemiAmp => true,
+            Token::Word(word) => word.literal().is_some_and(|word| LIST_TERMINATORS.contains(&word)),
+            _ => false,
+        })
+    }
+
+    // takes the reserved word that has to come next
+    fn expect(&mut self, reserved: &str) -> Result<(),SyntaxError> {
+        match self.next()? {
+            Token::Word(word) if word.literal() == Some(reserved) => Ok(()),
+            token => Err(unexpected(token)),
+        }
+    }
+
+    // and-or lists separated by ;, & or newlines
+    fn parse_list(&mut self) -> Result<List,SyntaxError> {
+        let mut list = List::default();
+
+        loop {
+            self.skip_newlines()?;
+            if self.at_list_end()? {
+                break;
+            }
+
+            let start = self.lexer.token_start();
+            let and_or = self.parse_and_or()?;
+            self.peek()?;
+            let mut end = self.lexer.token_start();
+
+            let bg = match self.peek()? {
+                Token::Amp => {
+                    self.next()?;
+                    end = self.lexer.position();
+                    true
+                },
+                Token::Semi => {
+                    self.next()?;
+                    false
+                },
+                Token::Newline => false,
+                _ => {
+                    list.items.push(ListItem {and_or, bg: false, text: self.lexer.text(start,end)});
+                    break;
+                },
+            };
+            list.items.push(ListItem {and_or, bg, text: self.lexer.text(start,end)});
+        }
+
+        Ok(list)
+    }
+
+    fn parse_and_or(&mut self) -> Result<AndOr,SyntaxError> {
+        let first = self.parse_pipeline()?;
+        let mut rest = Vec::new();
+
+        loop {
+            let connector = match self.peek()? {
+                Token::AndIf => Connector::And,
+                Token::OrIf => Connector::Or,
+                _ => break,
+            };
+            self.next()?;
+            self.skip_newlines()?;
+            rest.push((connector,self.parse_pipeline()?));
+        }
+
+        Ok(AndOr {first, rest})
+    }
+
+    fn parse_pipeline(&mut self) -> Result<Pipeline,SyntaxError> {
+        let negated = matches!(self.peek()?, Token::Word(word) if word.literal() == Some("!"));
+        if negated {
+            self.next()?;
+        }
+        let mut commands = vec![self.parse_command()?];
+
+        while matches!(self.peek()?, Token::Pipe | Token::PipeAnd) {
+            if self.next()? == Token::PipeAnd {
+                add_stderr_redirect(commands.last_mut().unwrap());
+            }
+            self.skip_newlines()?;
+            commands.push(self.parse_command()?);
+        }
+
+        Ok(Pipeline {commands, negated})
+    }
+
+    // the list inside a compound command, which can't be empty
+    fn parse_compound_list(&mut self) -> Result<List,SyntaxError> {
+        let list = self.parse_list()?;
+        if list.items.is_empty() {
+            let token = self.next()?;
+            return Err(unexpected(token));
+        }
+        Ok(list)
+    }
+
+    fn parse_command(&mut self) -> Result<Command,SyntaxError> {
+        let reserved = match self.peek()? {
+            Token::Arith(_) => return self.parse_arith_command().map(Command::Simple),
+            Token::LParen => {
+                let subshell = self.parse_subshell()?;
+                return self.parse_trailing_redirects(subshell);
+            },
+            Token::Word(word) => word.literal().map(|word| word.to_string()),
+            _ => None,
+        };
+        let compound = match reserved.as_deref() {
+            Some("if") => Command::If(self.parse_if()?),
+            Some("while") | Some("until") => Command::Loop(self.parse_loop()?),
+            Some("for") => self.parse_for()?,
+            Some("case") => Command::Case(self.parse_case()?),
+            Some("{") => self.parse_group()?,
+            Some("function") => {
+                self.next()?;
+                let name = match self.next()? {
+                    Token::Word(word) => word,
+                    token => return Err(unexpected(token)),
+                };
+                if *self.peek()? == Token::LParen {
+                    self.next()?;
+                    self.expect_token(Token::RParen)?;
+                }
+                return self.parse_function(name);
+            },
+            _ => {
+                let command = self.parse_simple_command()?;
+                // name ( ) starts a function definition
+                if *self.peek()? == Token::LParen && command.assignments.is_empty() && command.redirects.is_empty() && command.words.len() == 1 {
+                    self.next()?;
+                    self.expect_token(Token::RParen)?;
+                    return self.parse_function(command.words[0].clone());
+                }
+                return Ok(Command::Simple(command));
+            },
+        };
+        self.parse_trailing_redirects(compound)
+    }
+
+    // redirections after a compound command apply to all of it
+    fn parse_trailing_redirects(&mut self, command: Command) -> Result<Command,SyntaxError> {
+        let mut redirects = Vec::new();
+        while self.at_redirect()? {
+            redirects.push(self.parse_redirect()?);
+        }
+
+        if redirects.is_empty() {
+            return Ok(command);
+        }
+        Ok(Command::Redirected(Box::new(command),redirects))
+    }
+
+    // ( list )
+    fn parse_subshell(&mut self) -> Result<Command,SyntaxError> {
+        self.expect_token(Token::LParen)?;
+        let body = self.parse_compound_list()?;
+        self.expect_token(Token::RParen)?;
+        Ok(Command::Subshell(body))
+    }
+
+    fn expect_token(&mut self, expected: Token) -> Result<(),SyntaxError> {
+        match self.next()? {
+            token if token == expected => Ok(()),
+            token => Err(unexpected(token)),
+        }
+    }
+
+    // { list; }
+    fn parse_group(&mut self) -> Result<Command,SyntaxError> {
+        self.expect("{")?;
+        let body = self.parse_compound_list()?;
+        self.expect("}")?;
+        Ok(Command::Group(body))
+    }
+
+    // the body of a function has to be a compound command
+    fn parse_function(&mut self, name: Word) -> Result<Command,SyntaxError> {
+        let name = match name.literal() {
+            Some(name) if !name.is_empty() && !name.contains('=') => name.to_string(),
+            _ => return Err(SyntaxError::Unexpected(name.to_string())),
+        };
+
+        self.skip_newlines()?;
+        let body = match self.peek()? {
+            Token::Word(word) if matches!(word.literal(), Some("{" | "if" | "while" | "until" | "for" | "case")) => self.parse_command()?,
+            Token::LParen => self.parse_command()?,
+            Token::Eof => return Err(SyntaxError::Incomplete),
+            _ => {
+                let token = self.next()?;
+                return Err(unexpected(token));
+            }
+        };
+        Ok(Command::Function(FunctionDef {name, body: Box::new(body)}))
+    }
+
+    fn parse_if(&mut self) -> Result<IfClause,SyntaxError> {
+        self.expect("if")?;
+        let mut branches = Vec::new();
+        let mut else_branch = None;
+
+        loop {
+            let condition = self.parse_compound_list()?;
+            self.expect("then")?;
+            let body = self.parse_compound_list()?;
+            branches.push((condition,body));
+
+            match self.next()? {
+                Token::Word(word) if word.literal() == Some("elif") => continue,
+                Token::Word(word) if word.literal() == Some("else") => {
+                    else_branch = Some(self.parse_compound_list()?);
+                    self.expect("fi")?;
+                },
+                Token::Word(word) if word.literal() == Some("fi") => (),
+                token => return Err(unexpected(token)),
+            }
+            break;
+        }
+
+        Ok(IfClause {branches, else_branch})
+    }
+
+    fn parse_case(&mut self) -> Result<CaseClause,SyntaxError> {
+        self.expect("case")?;
+        let word = match self.next()? {
+            Token::Word(word) => word,
+            token => return Err(unexpected(token)),
+        };
+        self.skip_newlines()?;
+        self.expect("in")?;
+
+        let mut items = Vec::new();
+        loop {
+            self.skip_newlines()?;
+            match self.next()? {
+                Token::Word(word) if word.literal() == Some("esac") => break,
+                Token::LParen => (),
+                token => self.peeked = Some(token),
+            }
+
+            let mut patterns = Vec::new();
+            loop {
+                match self.next()? {
+                    Token::Word(pattern) => patterns.push(pattern),
+                    token => return Err(unexpected(token)),
+                }
+                match self.next()? {
+                    Token::Pipe => continue,
+                    Token::RParen => break,
+                    token => return Err(unexpected(token)),
+                }
+            }
+
+            let body = self.parse_list()?;
+            let terminator = match self.next()? {
+                Token::DSemi => CaseTerminator::Break,
+                Token::SemiAmp => CaseTerminator::FallThrough,
+                Token::DSemiAmp => CaseTerminator::Continue,
+                // the last item can go without a terminator
+                Token::Word(word) if word.literal() == Some("esac") => {
+                    items.push(CaseItem {patterns, body, terminator: CaseTerminator::Break});
+                    break;
+                },
+                token => return Err(unexpected(token)),
+            };
+            items.push(CaseItem {patterns, body, terminator});
+        }
+
+        Ok(CaseClause {word, items})
+    }
+
+    // do list done
+    fn parse_do_group(&mut self) -> Result<List,SyntaxError> {
+        self.expect("do")?;
+        let body = self.parse_compound_list()?;
+        self.expect("done")?;
+        Ok(body)
+    }
+
+    fn parse_loop(&mut self) -> Result<LoopClause,SyntaxError> {
+        let until = match self.next()? {
+            Token::Word(word) => word.literal() == Some("until"),
+            _ => unreachable!(),
+        };
+        let condition = self.parse_compound_list()?;
+        let body = self.parse_do_group()?;
+        Ok(LoopClause {until, condition, body})
+    }
+
+    fn parse_for(&mut self) -> Result<Command,SyntaxError> {
+        self.expect("for")?;
+
+        let name = match self.next()? {
+            Token::Arith(parts) => {
+                let mut exprs = split_arith_for(parts);
+                if exprs.len() != 3 {
+                    return Err(SyntaxError::Unexpected("((".to_string()));
+                }
+                if *self.peek()? == Token::Semi {
+                    self.next()?;
+                }
+                self.skip_newlines()?;
+                let body = self.parse_do_group()?;
+                let step = exprs.pop().unwrap();
+                let condition = exprs.pop().unwrap();
+                let init = exprs.pop().unwrap();
+                return Ok(Command::ArithFor(ArithForClause {init, condition, step, body}));
+            },
+            Token::Word(word) => match word.literal() {
+                Some(name) if is_name(name) => name.to_string(),
+                _ => return Err(SyntaxError::Unexpected(word.to_string())),
+            },
+            token => return Err(unexpected(token)),
+        };
+
+        self.skip_newlines()?;
+        let mut words = None;
+        if matches!(self.peek()?, Token::Word(word) if word.literal() == Some("in")) {
+            self.next()?;
+            let mut list = Vec::new();
+            loop {
+                match self.next()? {
+                    Token::Word(word) => list.push(word),
+                    Token::Semi | Token::Newline => break,
+                    token => return Err(unexpected(token)),
+                }
+            }
+            words = Some(list);
+        }
+        else if *self.peek()? == Token::Semi {
+            self.next()?;
+        }
+
+        self.skip_newlines()?;
+        let body = self.parse_do_group()?;
+        Ok(Command::For(ForClause {name, words, body}))
+    }
+
+    // ((expr)) is run as let "expr"
+    fn parse_arith_command(&mut self) -> Result<SimpleCommand,SyntaxError> {
+        let parts = match self.next()? {
+            Token::Arith(parts) => parts,
+            _ => unreachable!(),
+        };
+
+        let mut command = SimpleCommand::default();
+        command.words.push(Word {parts: vec![WordPart::Literal("let".to_string())]});
+        command.words.push(Word {parts: vec![WordPart::DoubleQuoted(parts)]});
+
+        while self.at_redirect()? {
+            command.redirects.push(self.parse_redirect()?);
+        }
+
+        Ok(command)
+    }
+
+    fn at_redirect(&mut self) -> Result<bool,SyntaxError> {
+        let token = self.peek()?;
+        Ok(matches!(token, Token::IoNumber(_)) || redirect_op(token).is_some())
+    }
+
+    // [n]op target
+    fn parse_redirect(&mut self) -> Result<Redirect,SyntaxError> {
+        let fd = match self.peek()? {
+            Token::IoNumber(fd) => {
+                let fd = *fd;
+                self.next()?;
+                Some(fd)
+            },
+            _ => None,
+        };
+        let token = self.next()?;
+        let op = match redirect_op(&token) {
+            Some(op) => op,
+            None => return Err(unexpected(token)),
+        };
+        let target = match self.next()? {
+            Token::Word(target) => target,
+            token => return Err(unexpected(token)),
+        };
+        // the target of << is the delimiter, the body takes its place
+        if op == RedirOp::HereDoc {
+            let body = self.lexer.read_heredoc(&target,token == Token::DLessDash)?;
+            return Ok(Redirect {fd, op, target: body});
+        }
+        Ok(Redirect {fd, op, target})
+    }
+
+    fn parse_simple_command(&mut self) -> Result<SimpleCommand,SyntaxError> {
+        let mut command = SimpleCommand::default();
+
+        loop {
+            if self.at_redirect()? {
+                command.redirects.push(self.parse_redirect()?);
+                continue;
+            }
+            match self.peek()? {
+                Token::Word(_) => {
+                    let word = match self.next()? {
+                        Token::Word(word) => word,
+                        _ => unreachable!(),
+                    };
+                    if command.words.is_empty() {
+                        if let Some(assignment) = split_assignment(&word) {
+                            command.assignments.push(assignment);
+                            continue;
+                        }
+                    }
+                    command.words.push(word);
+                },
+                _ => break,
+            }
+        }
+
+        if command.assignments.is_empty() && command.words.is_empty() && command.redirects.is_empty() {
+            let token = self.next()?;
+            return Err(unexpected(token));
+        }
+
+        Ok(command)
+    }
+}
+
+pub fn parse(input: &str) -> Result<List,SyntaxError> {
+    Parser::new(input).parse_program()
+}
+
+// finds the end of the command substitution whose body starts at pos and
+// returns the position just past its closing parenthesis
+pub fn substitution_end(chars: &[char], pos: usize) -> Result<usize,SyntaxError> {
+    let mut parser = Parser {lexer: Lexer::at(chars,pos), peeked: None};
+    parser.parse_until(Token::RParen)?;
+    Ok(parser.lexer.source_position())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(command: &Command) -> Vec<String> {
+        match command {
+            Command::Simple(simple) => simple.words.iter().map(|word| word.to_string()).collect(),
+            _ => panic!("not a simple command: {:?}",command),
+        }
+    }
+
+    #[test]
+    fn lists() {
+        let list = parse("a; b & c\nd").unwrap();
+        let texts: Vec<&str> = list.items.iter().map(|item| item.text.as_str()).collect();
+        assert_eq!(texts,["a","b &","c","d"]);
+        let bg: Vec<bool> = list.items.iter().map(|item| item.bg).collect();
+        assert_eq!(bg,[false,true,false,false]);
+    }
+
+    #[test]
+    fn and_or_pipelines() {
+        let list = parse("! a x | b && c || d").unwrap();
+        let and_or = &list.items[0].and_or;
+        assert!(and_or.first.negated);
+        assert_eq!(and_or.first.commands.len(),2);
+        assert_eq!(words(&and_or.first.commands[0]),["a","x"]);
+        let connectors: Vec<Connector> = and_or.rest.iter().map(|(connector, _)| *connector).collect();
+        assert_eq!(connectors,[Connector::And,Connector::Or]);
+        // a newline may follow an operator
+        assert_eq!(parse("a &&\nb").unwrap().items.len(),1);
+    }
+
+    #[test]
+    fn simple_commands() {
+        let list = parse("A=1 B=2 cmd x 2>err y").unwrap();
+        let simple = match &list.items[0].and_or.first.commands[0] {
+            Command::Simple(simple) => simple,
+            command => panic!("not a simple command: {:?}",command),
+        };
+        let names: Vec<&str> = simple.assignments.iter().map(|assignment| assignment.name.as_str()).collect();
+        assert_eq!(names,["A","B"]);
+        assert_eq!(words(&list.items[0].and_or.first.commands[0]),["cmd","x","y"]);
+        assert_eq!(simple.redirects.len(),1);
+        assert_eq!((simple.redirects[0].fd,simple.redirects[0].op),(Some(2),RedirOp::Output));
+        // an assignment only counts before the command name
+        assert_eq!(words(&parse("cmd A=1").unwrap().items[0].and_or.first.commands[0]),["cmd","A=1"]);
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(parse("a |").err(),Some(SyntaxError::Incomplete));
+        assert_eq!(parse("a && ").err(),Some(SyntaxError::Incomplete));
+        assert!(matches!(parse("| a"),Err(SyntaxError::Unexpected(_))));
+        assert!(matches!(parse("a ;; b"),Err(SyntaxError::Unexpected(_))));
+        assert!(parse("a >").is_err());
+    }
+
+    #[test]
+    fn names() {
+        assert!(is_name("_a1") && is_name("A"));
+        assert!(!is_name("") && !is_name("1a") && !is_name("a-b"));
+    }
+}
@@ -0,0 +1,250 @@
+// shell pattern matching as used by ${VAR#pat}, globbing and case
+
+#[derive(Debug,Clone,PartialEq)]
+enum ClassItem {
+    Char(char),
+    Range(char,char),
+    Named(String),
+}
+
+#[derive(Debug,Clone,PartialEq)]
+enum Token {
+    Char(char),
+    Any,
+    Star,
+    Class {negated: bool, items: Vec<ClassItem>},
+}
+
+#[derive(Debug,Clone)]
+pub struct Pattern {
+    tokens: Vec<Token>,
+}
+
+fn named_class(name: &str, c: char) -> bool {
+    match name {
+        "alnum" => c.is_alphanumeric(),
+        "alpha" => c.is_alphabetic(),
+        "blank" => c == ' ' || c == '\t',
+        "cntrl" => c.is_control(),
+        "digit" => c.is_ascii_digit(),
+        "graph" => !c.is_control() && !c.is_whitespace(),
+        "lower" => c.is_lowercase(),
+        "print" => !c.is_control(),
+        "punct" => c.is_ascii_punctuation(),
+        "space" => c.is_whitespace(),
+        "upper" => c.is_uppercase(),
+        "xdigit" => c.is_ascii_hexdigit(),
+        _ => false,
+    }
+}
+
+// parses the inside of a bracket expression starting just past the [.
+// returns None when there is no closing ], the [ is then an ordinary character
+fn parse_class(chars: &[char], start: usize) -> Option<(Token,usize)> {
+    let mut pos = start;
+    let mut negated = false;
+    if matches!(chars.get(pos), Some('!') | Some('^')) {
+        negated = true;
+        pos += 1;
+    }
+
+    let mut items = Vec::new();
+    let mut first = true;
+    loop {
+        let c = *chars.get(pos)?;
+        if c == ']' && !first {
+            return Some((Token::Class {negated, items}, pos + 1));
+        }
+        first = false;
+
+        let c = if c == '[' && chars.get(pos + 1) == Some(&':') {
+            let rest: String = chars[pos + 2..].iter().collect();
+            if let Some(end) = rest.find(":]") {
+                items.push(ClassItem::Named(rest[..end].to_string()));
+                pos += 2 + rest[..end].chars().count() + 2;
+                continue;
+            }
+            c
+        }
+        else if c == '\\' {
+            pos += 1;
+            *chars.get(pos)?
+        }
+        else {
+            c
+        };
+        pos += 1;
+
+        if chars.get(pos) == Some(&'-') && chars.get(pos + 1).is_some_and(|end| *end != ']') {
+            let mut end = chars[pos + 1];
+            pos += 2;
+            if end == '\\' {
+                end = *chars.get(pos)?;
+                pos += 1;
+            }
+            items.push(ClassItem::Range(c,end));
+        }
+        else {
+            items.push(ClassItem::Char(c));
+        }
+    }
+}
+
+impl Pattern {
+    pub fn new(pattern: &str) -> Self {
+        let chars: Vec<char> = pattern.chars().collect();
+        let mut tokens = Vec::new();
+        let mut pos = 0;
+
+        while pos < chars.len() {
+            let c = chars[pos];
+            pos += 1;
+            match c {
+                '*' => {
+                    if tokens.last() != Some(&Token::Star) {
+                        tokens.push(Token::Star);
+                    }
+                },
+                '?' => tokens.push(Token::Any),
+                '[' => match parse_class(&chars,pos) {
+                    Some((class, end)) => {
+                        tokens.push(class);
+                        pos = end;
+                    },
+                    None => tokens.push(Token::Char(c)),
+                },
+                '\\' if pos < chars.len() => {
+                    tokens.push(Token::Char(chars[pos]));
+                    pos += 1;
+                },
+                _ => tokens.push(Token::Char(c)),
+            }
+        }
+
+        Self {tokens}
+    }
+
+    fn matches_one(token: &Token, c: char) -> bool {
+        match token {
+            Token::Char(expected) => *expected == c,
+            Token::Any => true,
+            Token::Star => true,
+            Token::Class {negated, items} => {
+                let found = items.iter().any(|item| match item {
+                    ClassItem::Char(expected) => *expected == c,
+                    ClassItem::Range(start, end) => *start <= c && c <= *end,
+                    ClassItem::Named(name) => named_class(name,c),
+                });
+                found != *negated
+            },
+        }
+    }
+
+    pub fn matches(&self, text: &str) -> bool {
+        let text: Vec<char> = text.chars().collect();
+        let mut t = 0;
+        let mut p = 0;
+        // where to pick up again if what follows the last * doesn't match
+        let mut backtrack: Option<(usize,usize)> = None;
+
+        while t < text.len() {
+            match self.tokens.get(p) {
+                Some(Token::Star) => {
+                    p += 1;
+                    backtrack = Some((p,t));
+                    continue;
+                },
+                Some(token) if Self::matches_one(token,text[t]) => {
+                    p += 1;
+                    t += 1;
+                    continue;
+                },
+                _ => (),
+            }
+
+            match backtrack {
+                Some((star_p, star_t)) => {
+                    p = star_p;
+                    t = star_t + 1;
+                    backtrack = Some((star_p,star_t + 1));
+                },
+                None => return false,
+            }
+        }
+
+        self.tokens[p..].iter().all(|token| *token == Token::Star)
+    }
+
+    // whether the pattern can only match its own text
+    pub fn is_literal(&self) -> bool {
+        self.tokens.iter().all(|token| matches!(token, Token::Char(_)))
+    }
+
+    // the text matched by a pattern without any wildcards
+    pub fn literal_text(&self) -> String {
+        self.tokens.iter().filter_map(|token| match token {
+            Token::Char(c) => Some(*c),
+            _ => None,
+        }).collect()
+    }
+
+}
+
+// the characters that have to be escaped to match themselves
+pub fn is_special(c: char) -> bool {
+    matches!(c, '*' | '?' | '[' | ']' | '\\')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(pattern: &str, text: &str) -> bool {
+        Pattern::new(pattern).matches(text)
+    }
+
+    #[test]
+    fn wildcards() {
+        assert!(matches("*","") && matches("*","abc"));
+        assert!(matches("a?c","abc") && !matches("a?c","ac"));
+        assert!(matches("*.rs","main.rs") && !matches("*.rs","main.rsx"));
+        assert!(matches("a*b*c","aXbYbZc"));
+        assert!(!matches("a*b*c","aXbYbZ"));
+    }
+
+    #[test]
+    fn backtracking() {
+        assert!(matches("*ab","aab"));
+        assert!(matches("*aab","aaaab"));
+        assert!(matches("a*a*a","aaa"));
+        assert!(matches("*a*b*","xxaxxbxx"));
+        assert!(!matches("*a*b","xxbxxa"));
+        assert!(matches("*?","a") && !matches("*??","a"));
+    }
+
+    #[test]
+    fn classes() {
+        assert!(matches("[abc]","b") && !matches("[abc]","d"));
+        assert!(matches("[a-c]x","bx") && !matches("[a-c]x","dx"));
+        assert!(matches("[!a-c]","d") && matches("[^a-c]","d") && !matches("[!a-c]","a"));
+        assert!(matches("[]]","]") && matches("[!]]","a"));
+        assert!(matches("[a-]","-"));
+        assert!(matches("[[:digit:]][[:upper:]]","1A") && !matches("[[:digit:]]","a"));
+        assert!(matches("[[:alpha:]_]*","_x1"));
+    }
+
+    #[test]
+    fn escapes_and_unclosed() {
+        assert!(matches("\\*","*") && !matches("\\*","a"));
+        assert!(matches("a\\?","a?") && !matches("a\\?","ab"));
+        assert!(matches("[ab","[ab") && !matches("[ab","a"));
+        assert!(matches("[\\]]","]"));
+    }
+
+    #[test]
+    fn literals() {
+        assert!(Pattern::new("a\\*b").is_literal());
+        assert_eq!(Pattern::new("a\\*b").literal_text(),"a*b");
+        assert!(!Pattern::new("a*b").is_literal());
+    }
+}
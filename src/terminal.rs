@@ -0,0 +1,125 @@
+use nix::fcntl::{fcntl,FcntlArg};
+use nix::sys::signal::{self,SigHandler,Signal};
+use nix::sys::termios::{self,SetArg,Termios};
+use nix::unistd::{self,Pid};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool,AtomicI32,Ordering};
+
+// the shell's own copy of its controlling terminal, -1 when it has none or
+// is a forked copy that must leave it alone
+static TERMINAL: AtomicI32 = AtomicI32::new(-1);
+// the modes the shell reads its commands in
+static SHELL_MODES: Mutex<Option<Termios>> = Mutex::new(None);
+
+// set in a $(...) of an interactive shell, which shares the shell's process
+// group and so must not be stopped from the terminal, nor anything it runs
+static KEEP_IGNORED: AtomicBool = AtomicBool::new(false);
+
+const JOB_CONTROL_SIGNALS: [Signal; 3] = [Signal::SIGTSTP,Signal::SIGTTIN,Signal::SIGTTOU];
+
+// puts the shell in a process group of its own, in the foreground of the
+// terminal on stdin, if there is one. returns whether there is
+pub fn init() -> bool {
+    if !unistd::isatty(0).unwrap_or(false) {
+        return false;
+    }
+
+    // started in the background, wait until we are brought forward
+    loop {
+        let group = unistd::getpgrp();
+        match unistd::tcgetpgrp(0) {
+            Ok(foreground) if foreground != group => {
+                let _ = signal::kill(Pid::from_raw(-group.as_raw()),Signal::SIGTTIN);
+            },
+            _ => break,
+        }
+    }
+
+    for sig in JOB_CONTROL_SIGNALS {
+        let _ = unsafe { signal::signal(sig,SigHandler::SigIgn) };
+    }
+
+    let fd = match fcntl(0,FcntlArg::F_DUPFD_CLOEXEC(10)) {
+        Ok(fd) => fd,
+        Err(_) => return false,
+    };
+    // a session leader already leads its group and can't move
+    let _ = unistd::setpgid(Pid::from_raw(0),Pid::from_raw(0));
+    if let Err(e) = unistd::tcsetpgrp(fd,unistd::getpgrp()) {
+        eprintln!("tsh: cannot take the terminal: {}",e.desc());
+    }
+    *SHELL_MODES.lock().unwrap() = termios::tcgetattr(fd).ok();
+    TERMINAL.store(fd,Ordering::Relaxed);
+    true
+}
+
+pub fn active() -> bool {
+    TERMINAL.load(Ordering::Relaxed) >= 0
+}
+
+pub fn is_terminal_fd(fd: i32) -> bool {
+    fd >= 0 && fd == TERMINAL.load(Ordering::Relaxed)
+}
+
+// a forked shell doesn't take part in job control
+pub fn forget() {
+    TERMINAL.store(-1,Ordering::Relaxed);
+    if KEEP_IGNORED.load(Ordering::Relaxed) {
+        return;
+    }
+    for sig in JOB_CONTROL_SIGNALS {
+        let _ = unsafe { signal::signal(sig,SigHandler::SigDfl) };
+    }
+}
+
+// ignores Ctrl-Z and background terminal access from here on, in this
+// process and the ones it starts, as the shell does itself
+pub fn ignore_stops() {
+    KEEP_IGNORED.store(true,Ordering::Relaxed);
+    for sig in JOB_CONTROL_SIGNALS {
+        let _ = unsafe { signal::signal(sig,SigHandler::SigIgn) };
+    }
+}
+
+// runs between fork and exec. a foreground job takes the terminal itself,
+// so it doesn't depend on when the shell gets to it, and the signals the
+// shell ignores go back to their defaults, since exec would keep them ignored
+pub fn enter_child(foreground: bool) {
+    let fd = TERMINAL.load(Ordering::Relaxed);
+    if fd < 0 {
+        return;
+    }
+    if foreground {
+        let _ = unistd::tcsetpgrp(fd,unistd::getpgrp());
+    }
+    for sig in JOB_CONTROL_SIGNALS {
+        let _ = unsafe { signal::signal(sig,SigHandler::SigDfl) };
+    }
+}
+
+// hands the terminal to a foreground job, in the modes it was stopped in
+pub fn give(pgid: i32, modes: Option<&Termios>) {
+    let fd = TERMINAL.load(Ordering::Relaxed);
+    if fd < 0 {
+        return;
+    }
+    if let Some(modes) = modes {
+        let _ = termios::tcsetattr(fd,SetArg::TCSADRAIN,modes);
+    }
+    let _ = unistd::tcsetpgrp(fd,Pid::from_raw(pgid));
+}
+
+// takes the terminal back once the foreground job stops or ends and
+// returns the modes the job left it in
+pub fn take_back() -> Option<Termios> {
+    let fd = TERMINAL.load(Ordering::Relaxed);
+    if fd < 0 {
+        return None;
+    }
+    let modes = termios::tcgetattr(fd).ok();
+    let _ = unistd::tcsetpgrp(fd,unistd::getpgrp());
+    if let Some(shell_modes) = SHELL_MODES.lock().unwrap().as_ref() {
+        let _ = termios::tcsetattr(fd,SetArg::TCSADRAIN,shell_modes);
+    }
+    modes
+}